@@ -1,5 +1,9 @@
 use regex::Regex;
-use std::{fmt, path::PathBuf, str::FromStr};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use structopt::StructOpt;
 use url::Url;
 
@@ -22,14 +26,285 @@ pub enum Arguments {
     Backup(Backup),
     /// Restore documents from local backup files into a Apache Solr core
     Restore(Restore),
+    /// Copy documents from a Apache Solr core straight into another core without local files
+    Copy(Copy),
+    /// Check the zip backup files against the checksums and counts recorded in their manifests
+    Verify(Verify),
     /// Perform a commit in the Solr core index for persisting documents in disk/memory
     Commit(Command),
     /// Removes documents from the Solr core definitively
     Delete(Delete),
 }
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Clone, Debug)]
 pub struct Backup {
+    /// Existing folder where the zip backup files containing the extracted documents are stored
+    #[structopt(short, display_order = 30, long, parse(from_os_str), env = SOLR_COPY_DIR, value_name = "/path/to/output")]
+    pub dir: PathBuf,
+
+    /// Max number of files of documents stored in each zip file
+    #[structopt(long, display_order = 71, default_value = "40", parse(try_from_str = parse_quantity), min_values = 1, value_name = "quantity")]
+    pub archive_files: usize,
+
+    /// Optional prefix for naming the zip backup files when storing documents
+    #[structopt(long, display_order = 72, parse(try_from_str = parse_file_prefix), value_name = "name")]
+    pub zip_prefix: Option<String>,
+
+    /// Continue a interrupted backup skipping the steps recorded in its journal.
+    /// Use the same parameters and `--zip-prefix` of the interrupted backup.
+    #[structopt(long, display_order = 76)]
+    pub resume: bool,
+
+    /// Save the schema, the config overlay and the request params of the core
+    /// in a `.schema` folder next to the zip files for recreating the core later
+    #[structopt(long, display_order = 77)]
+    pub with_schema: bool,
+
+    /// Format of the json files in the zip archives: a array of docs or one doc per line
+    #[structopt(long, display_order = 73, default_value = "json", parse(try_from_str = parse_archive_format), possible_values = FORMAT_VALUES, value_name = "json | jsonl")]
+    pub format: ArchiveFormat,
+
+    /// File with a query in each line for backing up all of them in the same run.
+    /// Start the line with a label and a tab for naming its archives, like `tenant1<tab>tenant:1`.
+    /// Lines without label are named by the line number and lines starting with # are ignored
+    #[structopt(
+        long,
+        display_order = 46,
+        parse(from_os_str),
+        conflicts_with = "query",
+        value_name = "/path/to/queries.txt"
+    )]
+    pub query_file: Option<PathBuf>,
+
+    /// Label of the query read from `--query-file` used in the names of its archives
+    #[structopt(skip)]
+    pub label: Option<String>,
+
+    #[structopt(flatten)]
+    pub source: QueryArgs,
+
+    #[structopt(flatten)]
+    pub options: CommonArgs,
+
+    #[structopt(flatten)]
+    pub transfer: ParallelArgs,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Restore {
+    /// Existing folder where the zip backup files with the documents to restore are stored
+    #[structopt(short, display_order = 30, long, parse(from_os_str), env = SOLR_COPY_DIR, value_name = "/path/to/output")]
+    pub dir: PathBuf,
+
+    /// Mode to perform commits of the documents transaction log while updating the core
+    /// [possible values: none, soft, hard, <interval>]
+    #[structopt(short, long, display_order = 40, default_value = "hard", parse(try_from_str = parse_commit_mode), value_name = "mode")]
+    pub flush: CommitMode,
+
+    /// Do not perform a final hard commit before finishing
+    #[structopt(long, display_order = 41)]
+    pub no_final_commit: bool,
+
+    /// Disable core replication at start and enable again at end
+    #[structopt(long, display_order = 42)]
+    pub disable_replication: bool,
+
+    /// Search pattern for matching names of the zip backup files
+    #[structopt(short, long, display_order = 70, value_name = "core*.zip")]
+    pub search: Option<String>,
+
+    /// Optional order for searching the zip archives
+    #[structopt(long, display_order = 71, default_value = "none", parse(try_from_str = parse_sort_order), possible_values = SORT_VALUES, hide_possible_values = true,hide_default_value = true, value_name = "asc | desc")]
+    pub order: SortOrder,
+
+    #[structopt(flatten)]
+    pub transform: TransformArgs,
+
+    /// Continue a interrupted restore skipping the archive entries already indexed.
+    /// Every restore records the entries accepted by Solr in a journal in the `--dir` folder or in `--journal`.
+    #[structopt(long, display_order = 72)]
+    pub resume: bool,
+
+    /// Existing folder for storing the batches rejected by Solr as zip files for restoring later.
+    /// The error message of each batch is written in a `.errors.jsonl` file in the same folder.
+    #[structopt(long, display_order = 73, parse(from_os_str), value_name = "/path/to/rejected")]
+    pub dead_letter: Option<PathBuf>,
+
+    /// Split the batches rejected by Solr in halves and resend them for isolating the bad documents
+    #[structopt(long, display_order = 74)]
+    pub bisect: bool,
+
+    /// List the archives and check their fields against the schema of the core without updating it
+    #[structopt(long, display_order = 75)]
+    pub dry_run: bool,
+
+    /// Group the docs by shard with the hash ranges of the SolrCloud collection from CLUSTERSTATUS
+    /// and send each group straight to the leader of the shard
+    #[structopt(long, display_order = 76)]
+    pub by_shard: bool,
+
+    /// Add the field types, fields and copy fields of the schema saved with `backup --with-schema`
+    /// that are missing in the core before restoring the documents
+    #[structopt(long, display_order = 77)]
+    pub apply_schema: bool,
+
+    /// Create the core with the CoreAdmin API using this configset when it does not exist
+    #[structopt(long, display_order = 78, value_name = "configset")]
+    pub create_core: Option<String>,
+
+    /// Create the SolrCloud collection with the Collections API using this configset when it does not exist
+    #[structopt(
+        long,
+        display_order = 79,
+        conflicts_with = "create-core",
+        value_name = "configset"
+    )]
+    pub create_collection: Option<String>,
+
+    /// Number of shards of the collection created with `--create-collection`
+    #[structopt(
+        long,
+        display_order = 80,
+        default_value = "1",
+        min_values = 1,
+        value_name = "count"
+    )]
+    pub num_shards: usize,
+
+    /// Number of replicas of each shard of the collection created with `--create-collection`
+    #[structopt(
+        long,
+        display_order = 81,
+        default_value = "1",
+        min_values = 1,
+        value_name = "count"
+    )]
+    pub replication_factor: usize,
+
+    /// Number of documents read from the `jsonl` archives and sent to solr in each update
+    #[structopt(long, display_order = 82, default_value = "4k", parse(try_from_str = parse_quantity), min_values = 1, value_name = "quantity")]
+    pub batch_docs: usize,
+
+    /// File recording the entries indexed, for example when `--dir` is read-only [default: <dir>/restore_<core>.journal]
+    #[structopt(
+        long,
+        display_order = 83,
+        parse(from_os_str),
+        value_name = "/path/to/restore.journal"
+    )]
+    pub journal: Option<PathBuf>,
+
+    /// Start the restore over discarding the journal left by a previous restore instead of resuming it
+    #[structopt(long, display_order = 84, conflicts_with = "resume")]
+    pub discard_journal: bool,
+
+    #[structopt(flatten)]
+    pub options: CommonArgs,
+
+    #[structopt(flatten)]
+    pub transfer: ParallelArgs,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Copy {
+    /// Url pointing to the Solr cluster receiving the documents [default: --url].
+    /// It is accessed with the same credentials, TLS and proxy options of the source
+    #[structopt(long, display_order = 11, parse(try_from_str = parse_solr_url), value_name = "localhost:8983/solr")]
+    pub into_url: Option<String>,
+
+    /// Case sensitive name of the core in the Solr server receiving the documents
+    #[structopt(long, display_order = 21, value_name = "core")]
+    pub into_core: String,
+
+    /// Mode to perform commits of the documents transaction log while updating the core
+    /// [possible values: none, soft, hard, <interval>]
+    #[structopt(short, long, display_order = 45, default_value = "hard", parse(try_from_str = parse_commit_mode), value_name = "mode")]
+    pub flush: CommitMode,
+
+    /// Do not perform a final hard commit before finishing
+    #[structopt(long, display_order = 46)]
+    pub no_final_commit: bool,
+
+    /// Disable replication of the target core at start and enable again at end
+    #[structopt(long, display_order = 47)]
+    pub disable_replication: bool,
+
+    /// Extra parameter for the Update Handler of the target core, as `--params` is sent to the source
+    #[structopt(long, display_order = 48, value_name = "useParams=mypars")]
+    pub into_params: Option<String>,
+
+    /// How many times should continue on errors updating the target core
+    #[structopt(long, display_order = 49, default_value = "0", min_values = 0, value_name = "count", parse(try_from_str = parse_quantity_max))]
+    pub into_max_errors: usize,
+
+    /// Delay between each update of the target core. Format as: 3s, 500ms, 1min
+    #[structopt(long, display_order = 49, default_value = "0", min_values = 0, value_name = "time", parse(try_from_str = parse_millis), hide_default_value = true)]
+    pub into_delay: usize,
+
+    /// Existing folder for storing the batches rejected by the target core as zip files for restoring later
+    #[structopt(long, display_order = 73, parse(from_os_str), value_name = "/path/to/rejected")]
+    pub dead_letter: Option<PathBuf>,
+
+    /// Split the batches rejected by the target core in halves and resend them for isolating the bad documents
+    #[structopt(long, display_order = 74)]
+    pub bisect: bool,
+
+    #[structopt(flatten)]
+    pub transform: TransformArgs,
+
+    #[structopt(flatten)]
+    pub source: QueryArgs,
+
+    #[structopt(flatten)]
+    pub options: CommonArgs,
+
+    #[structopt(flatten)]
+    pub transfer: ParallelArgs,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Verify {
+    /// Existing folder where the zip backup files and their manifests are stored
+    #[structopt(short, display_order = 30, long, parse(from_os_str), env = SOLR_COPY_DIR, value_name = "/path/to/output")]
+    pub dir: PathBuf,
+
+    /// Search pattern for matching names of the backup manifests
+    #[structopt(short, long, display_order = 70, value_name = "core*")]
+    pub search: Option<String>,
+
+    #[structopt(flatten)]
+    pub logging: LoggingArgs,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Delete {
+    /// Solr Query for filtering which documents are removed in the core.
+    /// Use '*:*' for excluding all documents in the core.
+    /// There are no way of recovering excluded docs.
+    /// Use with caution and check twice.
+    #[structopt(short, long, display_order = 40, value_name = "f1:val1 AND f2:val2")]
+    pub query: String,
+
+    /// Wether to perform a commits of transaction log after removing the documents
+    #[structopt(short, display_order = 41, long, default_value = "soft", parse(try_from_str = parse_commit_mode), value_name = "mode", possible_values = COMMIT_AFTER_VALUES)]
+    pub flush: CommitMode,
+
+    #[structopt(flatten)]
+    pub options: CommonArgs,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Command {
+    #[structopt(flatten)]
+    pub options: CommonArgs,
+}
+
+// endregion
+
+// region Cli common
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct QueryArgs {
     /// Solr Query param 'q' for filtering which documents are retrieved
     /// See: https://lucene.apache.org/solr/guide/6_6/the-standard-query-parser.html
     #[structopt(short, long, display_order = 40, value_name = "'f1:vl1 AND f2:vl2'")]
@@ -47,10 +322,20 @@ pub struct Backup {
     #[structopt(short, long, display_order = 43, parse(try_from_str = parse_quantity), min_values = 1, value_name = "quantity")]
     pub limit: Option<usize>,
 
-    /// Names of core fields retrieved in each document [default: all but _*]
+    /// Names of core fields retrieved in each document. Accepts globs like attr_*
+    /// [default: all stored fields but _* and copyField targets]
     #[structopt(short, long, display_order = 44, value_name = "field1> <field2")]
     pub select: Vec<String>,
 
+    /// Names of core fields not retrieved in the documents. Accepts globs like *_txt
+    #[structopt(short = "x", long, display_order = 44, value_name = "field1> <field2")]
+    pub exclude: Vec<String>,
+
+    /// Solr filter query param 'fq' for restricting the documents retrieved. Can be repeated.
+    /// Filters are cached by Solr independently of the `--query`
+    #[structopt(long, display_order = 45, value_name = "'f1:vl1'")]
+    pub filter: Vec<String>,
+
     /// Slice the queries by using the variables {begin} and {end} for iterating in `--query`
     /// Used in bigger solr cores with huge number of docs because querying the end of docs is expensive and fails frequently
     #[structopt(short, long, display_order = 50, default_value = "day", parse(try_from_str = parse_iterate_mode), possible_values = ITERATE_VALUES, value_name = "mode")]
@@ -58,13 +343,12 @@ pub struct Backup {
 
     /// The range of dates/numbers for iterating the queries throught slices.
     /// Requires that the query parameter contains the variables {begin} and {end} for creating the slices.
-    /// Use numbers or dates in ISO 8601 format (yyyy-mm-ddTHH:MM:SS)
+    /// Use numbers or dates in ISO 8601 format (yyyy-mm-ddTHH:MM:SS) or Solr date math like NOW/DAY-7DAYS
     #[structopt(
         short = "b",
         long = "between",
         display_order = 51,
         value_name = "begin> <end",
-        requires = "query",
         number_of_values = 2
     )]
     pub iterate_between: Vec<String>,
@@ -80,18 +364,30 @@ pub struct Backup {
     )]
     pub iterate_step: usize,
 
+    /// Name of a date/integer field for finding the range for iterating instead of `--between`.
+    /// Uses the min and max values of the field in the docs of `--query` and skips the slices without docs
+    #[structopt(
+        long,
+        display_order = 53,
+        value_name = "field",
+        conflicts_with = "iterate-between"
+    )]
+    pub iterate_field: Option<String>,
+
+    /// Size the slices for holding about this quantity of docs (like 100k) instead of using a fixed `--step`.
+    /// Slices are merged or split by counting the docs in them. Requires `--iterate-field`
+    #[structopt(long, display_order = 54, parse(try_from_str = parse_quantity), min_values = 1, requires = "iterate-field", value_name = "quantity")]
+    pub slice_docs: Option<usize>,
+
+    /// Time zone of the dates in `--between` and of the calendar used for slicing, like America/Sao_Paulo.
+    /// Also sent to Solr as the TZ param for its date math [default: UTC]
+    #[structopt(long, display_order = 55, value_name = "zone")]
+    pub time_zone: Option<String>,
+
     /// Number of documents to retrieve from solr in each reader step
     #[structopt(long, display_order = 70, default_value = "4k", parse(try_from_str = parse_quantity), min_values = 1, value_name = "quantity")]
     pub num_docs: usize,
 
-    /// Max number of files of documents stored in each zip file
-    #[structopt(long, display_order = 71, default_value = "40", parse(try_from_str = parse_quantity), min_values = 1, value_name = "quantity")]
-    pub archive_files: usize,
-
-    /// Optional prefix for naming the zip backup files when storing documents
-    #[structopt(long, display_order = 72, parse(try_from_str = parse_file_prefix), value_name = "name")]
-    pub zip_prefix: Option<String>,
-
     /// Use only when your Solr Cloud returns a distinct count of docs for some queries in a row.
     /// This may be caused by replication problems between cluster nodes of shard replicas of a core.
     /// Response with 'num_found' bellow the greatest value are ignored for getting all possible docs.
@@ -107,80 +403,155 @@ pub struct Backup {
     )]
     pub workaround_shards: usize,
 
-    #[structopt(flatten)]
-    pub options: CommonArgs,
+    /// Use Solr cursorMark for paging through documents instead of start/rows.
+    /// Avoids slow deep paging in huge cores. The uniqueKey field is added to the sort order.
+    #[structopt(long, display_order = 74)]
+    pub cursor: bool,
 
-    #[structopt(flatten)]
-    pub transfer: ParallelArgs,
+    /// Stream the documents through the Solr /export handler instead of /select.
+    /// Requires that all fields in `--select` and `--order` have docValues enabled.
+    #[structopt(long, display_order = 75, conflicts_with = "cursor")]
+    pub export: bool,
+
+    /// Discover the shards of the SolrCloud collection with CLUSTERSTATUS and read each shard
+    /// in parallel from a active replica with distrib=false instead of querying the collection
+    #[structopt(long, display_order = 76)]
+    pub by_shard: bool,
 }
 
 #[derive(StructOpt, Debug)]
-pub struct Restore {
-    /// Mode to perform commits of the documents transaction log while updating the core
-    /// [possible values: none, soft, hard, <interval>]
-    #[structopt(short, long, display_order = 40, default_value = "hard", parse(try_from_str = parse_commit_mode), value_name = "mode")]
-    pub flush: CommitMode,
+pub struct TransformArgs {
+    /// Rename fields in every document before updating the core
+    #[structopt(long, display_order = 50, parse(try_from_str = parse_key_value), value_name = "old=new")]
+    pub rename: Vec<(String, String)>,
+
+    /// Remove fields from every document before updating the core
+    #[structopt(long = "drop", display_order = 51, value_name = "field")]
+    pub drop_fields: Vec<String>,
+
+    /// Set fields to a constant value in every document. The value is parsed as json when possible
+    #[structopt(long = "set", display_order = 52, parse(try_from_str = parse_key_value), value_name = "field=value")]
+    pub set_fields: Vec<(String, String)>,
+
+    /// Rewrite the values of fields with a template using the variable {value}, like: id={value}_v2
+    #[structopt(long = "map", display_order = 53, parse(try_from_str = parse_key_value), value_name = "field=template")]
+    pub map_fields: Vec<(String, String)>,
+
+    /// Json file with transformations as: {"drop":["f1"],"rename":{"f2":"f3"},"set":{"f4":1},"map":{"id":"{value}_v2"}}
+    /// Applied in this order: drop, rename, map and set
+    #[structopt(long, display_order = 54, parse(from_os_str), value_name = "path")]
+    pub transform_file: Option<PathBuf>,
+}
 
-    /// Do not perform a final hard commit before finishing
-    #[structopt(long, display_order = 41)]
-    pub no_final_commit: bool,
+#[derive(StructOpt, Clone, Debug)]
+pub struct CommonArgs {
+    /// Url pointing to the Solr cluster
+    #[structopt(short, long, display_order = 10, env = SOLR_COPY_URL, parse(try_from_str = parse_solr_url), value_name = "localhost:8983/solr")]
+    pub url: String,
 
-    /// Disable core replication at start and enable again at end
-    #[structopt(long, display_order = 42)]
-    pub disable_replication: bool,
+    /// Case sensitive name of the core in the Solr server
+    #[structopt(short, long, display_order = 20, value_name = "core")]
+    pub core: String,
 
-    /// Search pattern for matching names of the zip backup files
-    #[structopt(short, long, display_order = 70, value_name = "core*.zip")]
-    pub search: Option<String>,
+    #[structopt(flatten)]
+    pub auth: AuthArgs,
 
-    /// Optional order for searching the zip archives
-    #[structopt(long, display_order = 71, default_value = "none", parse(try_from_str = parse_sort_order), possible_values = SORT_VALUES, hide_possible_values = true,hide_default_value = true, value_name = "asc | desc")]
-    pub order: SortOrder,
+    #[structopt(flatten)]
+    pub tls: TlsArgs,
 
     #[structopt(flatten)]
-    pub options: CommonArgs,
+    pub proxy: ProxyArgs,
 
     #[structopt(flatten)]
-    pub transfer: ParallelArgs,
+    pub logging: LoggingArgs,
 }
 
-#[derive(StructOpt, Debug)]
-pub struct Delete {
-    /// Solr Query for filtering which documents are removed in the core.
-    /// Use '*:*' for excluding all documents in the core.
-    /// There are no way of recovering excluded docs.
-    /// Use with caution and check twice.
-    #[structopt(short, long, display_order = 40, value_name = "f1:val1 AND f2:val2")]
-    pub query: String,
+#[derive(StructOpt, Clone, Debug)]
+pub struct AuthArgs {
+    /// User name for authenticating in Solr with the BasicAuth plugin
+    #[structopt(long, display_order = 30, env = SOLR_COPY_USER, value_name = "name")]
+    pub user: Option<String>,
+
+    /// Password of the user. Prefer the env variable or --password-file for hiding it
+    #[structopt(long, display_order = 31, env = SOLR_COPY_PASSWORD, hide_env_values = true, parse(from_str), value_name = "secret")]
+    pub password: Option<Secret>,
+
+    /// File containing the password of the user
+    #[structopt(long, display_order = 32, parse(from_os_str), value_name = "path")]
+    pub password_file: Option<PathBuf>,
+
+    /// Bearer token for authenticating in Solr with the JWT plugin
+    #[structopt(long, display_order = 33, env = SOLR_COPY_TOKEN, hide_env_values = true, parse(from_str), value_name = "secret")]
+    pub token: Option<Secret>,
+
+    /// File containing the bearer token
+    #[structopt(long, display_order = 34, parse(from_os_str), value_name = "path")]
+    pub token_file: Option<PathBuf>,
+
+    /// Extra http header sent in every request to Solr as in: 'X-Api-Key: secret'
+    #[structopt(long, display_order = 35, number_of_values = 1, parse(try_from_str = parse_header), value_name = "name: value")]
+    pub header: Vec<(String, Secret)>,
+}
 
-    /// Wether to perform a commits of transaction log after removing the documents
-    #[structopt(short, display_order = 41, long, default_value = "soft", parse(try_from_str = parse_commit_mode), value_name = "mode", possible_values = COMMIT_AFTER_VALUES)]
-    pub flush: CommitMode,
+#[derive(StructOpt, Clone, Debug)]
+pub struct TlsArgs {
+    /// PEM file with the certificates of the CAs trusted for validating the Solr server
+    #[structopt(long, display_order = 36, parse(from_os_str), value_name = "path")]
+    pub ca_bundle: Option<PathBuf>,
 
-    #[structopt(flatten)]
-    pub options: CommonArgs,
-}
+    /// PEM file with the client certificate for authenticating with mutual TLS
+    #[structopt(long, display_order = 37, parse(from_os_str), value_name = "path")]
+    pub client_cert: Option<PathBuf>,
 
-#[derive(StructOpt, Debug)]
-pub struct Command {
-    #[structopt(flatten)]
-    pub options: CommonArgs,
+    /// PEM file with the private key of the client certificate when not included in the certificate file
+    #[structopt(
+        long,
+        display_order = 38,
+        requires = "client-cert",
+        parse(from_os_str),
+        value_name = "path"
+    )]
+    pub client_key: Option<PathBuf>,
+
+    /// Do not validate the certificate of the Solr server. Unsafe: use only for testing
+    #[structopt(long, display_order = 39)]
+    pub insecure: bool,
 }
 
-// endregion
+#[derive(StructOpt, Clone, Debug)]
+pub struct ProxyArgs {
+    /// Http proxy for reaching Solr. Overrides the HTTP_PROXY and HTTPS_PROXY env variables.
+    /// The port is 8080 when the address has none
+    #[structopt(long, display_order = 40, value_name = "http://proxy:3128")]
+    pub proxy: Option<String>,
+
+    /// User name for authenticating in the proxy
+    #[structopt(long, display_order = 41, value_name = "name")]
+    pub proxy_user: Option<String>,
+
+    /// Password of the proxy user
+    #[structopt(long, display_order = 42, env = SOLR_COPY_PROXY_PASSWORD, hide_env_values = true, parse(from_str), value_name = "secret")]
+    pub proxy_password: Option<Secret>,
+}
 
-// region Cli common
+/// A password or token that is not printed in the logs
+#[derive(Clone, PartialEq)]
+pub struct Secret(pub String);
 
-#[derive(StructOpt, Clone, Debug)]
-pub struct CommonArgs {
-    /// Url pointing to the Solr cluster
-    #[structopt(short, long, display_order = 10, env = SOLR_COPY_URL, parse(try_from_str = parse_solr_url), value_name = "localhost:8983/solr")]
-    pub url: String,
+impl From<&str> for Secret {
+    fn from(src: &str) -> Self {
+        Secret(src.to_string())
+    }
+}
 
-    /// Case sensitive name of the core in the Solr server
-    #[structopt(short, long, display_order = 20, value_name = "core")]
-    pub core: String,
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
 
+#[derive(StructOpt, Clone, Debug)]
+pub struct LoggingArgs {
     /// What level of detail should print messages
     #[structopt(long, display_order = 90, value_name = "level", default_value = "info", possible_values = LOG_LEVEL_VALUES)]
     pub log_level: String,
@@ -198,13 +569,9 @@ pub struct CommonArgs {
     pub log_file_level: String,
 }
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Clone, Debug)]
 /// Dumps and restores documents from a Apache Solr core into local backup files
 pub struct ParallelArgs {
-    /// Existing folder where the zip backup files containing the extracted documents are stored
-    #[structopt(short, display_order = 30, long, parse(from_os_str), env = SOLR_COPY_DIR, value_name = "/path/to/output")]
-    pub dir: PathBuf,
-
     /// Extra parameter for Solr Update Handler.
     /// See: https://lucene.apache.org/solr/guide/transforming-and-indexing-custom-json.html
     #[structopt(short, long, display_order = 60, value_name = "useParams=mypars")]
@@ -273,10 +640,20 @@ pub enum IterateMode {
     Minute,
     Hour,
     Day,
+    /// Slices starting on mondays, on the first day of the month and on the first day of the year
+    Week,
+    Month,
+    Year,
     /// Break the query in slices by a first ordered integer field repeating between {begin} and {end} in the query parameters
     Range,
 }
 
+#[derive(StructOpt, Clone, Copy, PartialEq, Debug)]
+pub enum ArchiveFormat {
+    Json,
+    Jsonl,
+}
+
 #[derive(StructOpt, Clone, Copy, PartialEq, Debug)]
 pub enum SortOrder {
     None,
@@ -284,15 +661,20 @@ pub enum SortOrder {
     Desc,
 }
 
-const ITERATE_VALUES: &[&str] = &["minute", "hour", "day", "range"];
+const ITERATE_VALUES: &[&str] = &["minute", "hour", "day", "week", "month", "year", "range"];
 const COMMIT_AFTER_VALUES: &[&str] = &["none", "soft", "hard"];
 const SORT_VALUES: &[&str] = &["none", "asc", "desc"];
+const FORMAT_VALUES: &[&str] = &["json", "jsonl"];
 
 const LOG_LEVEL_VALUES: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
 const LOG_TERM_VALUES: &[&str] = &["stdout", "stderr", "mixed"];
 
 const SOLR_COPY_DIR: &str = "SOLR_COPY_DIR";
 const SOLR_COPY_URL: &str = "SOLR_COPY_URL";
+const SOLR_COPY_USER: &str = "SOLR_COPY_USER";
+const SOLR_COPY_PASSWORD: &str = "SOLR_COPY_PASSWORD";
+const SOLR_COPY_TOKEN: &str = "SOLR_COPY_TOKEN";
+const SOLR_COPY_PROXY_PASSWORD: &str = "SOLR_COPY_PROXY_PASSWORD";
 
 // endregion
 
@@ -332,8 +714,8 @@ fn parse_quantity(src: &str) -> Result<usize, String> {
 fn parse_quantity_max(s: &str) -> Result<usize, String> {
     let lower = s.to_ascii_lowercase();
     match lower.as_str() {
-        "max" => Ok(std::usize::MAX),
-        _ => match parse_quantity(&s) {
+        "max" => Ok(usize::MAX),
+        _ => match parse_quantity(s) {
             Ok(value) => Ok(value),
             Err(_) => Err(format!("'{}'. [alowed: all, <quantity>]", s)),
         },
@@ -369,18 +751,36 @@ fn parse_millis(src: &str) -> Result<usize, String> {
     }
 }
 
+fn parse_key_value(src: &str) -> Result<(String, String), String> {
+    match src.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("Wrong value: '{}'. Use as: name=value", src)),
+    }
+}
+
+fn parse_header(src: &str) -> Result<(String, Secret), String> {
+    match src.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() && !name.contains(' ') => {
+            Ok((name.trim().to_string(), Secret::from(value.trim())))
+        }
+        _ => Err(format!("Wrong header: '{}'. Use as: 'name: value'", src)),
+    }
+}
+
 fn parse_solr_url(src: &str) -> Result<String, String> {
     let url2 = if src.starts_with_any(&["http://", "https://"]) {
         src.to_owned()
     } else {
         "http://".append(src)
     };
-    let parsing = Url::parse(src);
+    let parsing = Url::parse(&url2);
     if let Err(reason) = parsing {
         return Err(format!("Error parsing Solr: {}", reason));
     }
     let parsed = parsing.unwrap();
-    if parsed.scheme() != "http" {
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
         return Err("Solr url scheme must be http or https as in: http:://server.domain:8983/solr"
             .to_string());
     }
@@ -422,7 +822,7 @@ fn parse_commit_mode(s: &str) -> Result<CommitMode, String> {
         "none" => Ok(CommitMode::None),
         "soft" => Ok(CommitMode::Soft),
         "hard" => Ok(CommitMode::Hard),
-        _ => match parse_millis(&s) {
+        _ => match parse_millis(s) {
             Ok(value) => Ok(CommitMode::Within { millis: value }),
             Err(_) => Err(format!("'{}'. [alowed: none soft hard <secs>]", s)),
         },
@@ -435,8 +835,11 @@ fn parse_iterate_mode(s: &str) -> Result<IterateMode, String> {
         "minute" => Ok(IterateMode::Minute),
         "hour" => Ok(IterateMode::Hour),
         "day" => Ok(IterateMode::Day),
+        "week" => Ok(IterateMode::Week),
+        "month" => Ok(IterateMode::Month),
+        "year" => Ok(IterateMode::Year),
         "range" => Ok(IterateMode::Range),
-        _ => Err(format!("'{}'. [alowed: none minute hour day range]", s)),
+        _ => Err(format!("'{}'. [alowed: none minute hour day week month year range]", s)),
     }
 }
 
@@ -450,6 +853,15 @@ fn parse_sort_order(s: &str) -> Result<SortOrder, String> {
     }
 }
 
+fn parse_archive_format(s: &str) -> Result<ArchiveFormat, String> {
+    let lower = s.to_ascii_lowercase();
+    match lower.as_str() {
+        "json" => Ok(ArchiveFormat::Json),
+        "jsonl" => Ok(ArchiveFormat::Jsonl),
+        _ => Err(format!("'{}'. [alowed: json jsonl]", s)),
+    }
+}
+
 // endregion
 
 // region Cli impl
@@ -459,23 +871,45 @@ impl Arguments {
         match self {
             Self::Backup(get) => get.validate(),
             Self::Restore(put) => put.validate(),
+            Self::Copy(cpy) => cpy.validate(),
+            Self::Verify(ver) => assert_dir_exists(&ver.dir),
             Self::Commit(_) | Self::Delete(_) => Ok(()),
         }
     }
 
-    pub fn get_options(&self) -> &CommonArgs {
+    /// Solr server of the subcommand. None when it doesn't connect to Solr
+    pub fn get_options(&self) -> Option<&CommonArgs> {
+        match &self {
+            Self::Backup(get) => Some(&get.options),
+            Self::Restore(put) => Some(&put.options),
+            Self::Copy(cpy) => Some(&cpy.options),
+            Self::Verify(_) => None,
+            Self::Commit(com) => Some(&com.options),
+            Self::Delete(del) => Some(&del.options),
+        }
+    }
+
+    pub fn get_logging(&self) -> &LoggingArgs {
         match &self {
-            Self::Backup(get) => &get.options,
-            Self::Restore(put) => &put.options,
-            Self::Commit(com) => &com.options,
-            Self::Delete(del) => &del.options,
+            Self::Backup(get) => &get.options.logging,
+            Self::Restore(put) => &put.options.logging,
+            Self::Copy(cpy) => &cpy.options.logging,
+            Self::Verify(ver) => &ver.logging,
+            Self::Commit(com) => &com.options.logging,
+            Self::Delete(del) => &del.options.logging,
         }
     }
 }
 
+impl LoggingArgs {
+    pub fn is_quiet(&self) -> bool {
+        self.log_level.eq_ignore_ascii_case("off")
+    }
+}
+
 impl CommonArgs {
     pub fn is_quiet(&self) -> bool {
-        self.log_level.to_ascii_lowercase() == "off"
+        self.logging.is_quiet()
     }
 
     pub fn to_command(&self) -> Command {
@@ -553,19 +987,79 @@ pub trait Validation {
     }
 }
 
+impl Validation for QueryArgs {
+    fn validate(&self) -> Result<(), String> {
+        if self.cursor && self.skip > 0 {
+            return Err("Option --skip cannot be used together with --cursor".to_string());
+        }
+        if self.export && self.skip > 0 {
+            return Err("Option --skip cannot be used together with --export".to_string());
+        }
+        if self.by_shard && (self.skip > 0 || self.limit.is_some() || self.workaround_shards > 0) {
+            return Err("Options --skip, --limit and --workaround-shards cannot be used together \
+                        with --by-shard"
+                .to_string());
+        }
+        Ok(())
+    }
+}
+
+impl QueryArgs {
+    /// Slicing replaces the variables {begin} and {end} of the queries
+    fn check_slicing(&self, has_queries: bool) -> Result<(), String> {
+        let sliced = !self.iterate_between.is_empty() || self.iterate_field.is_some();
+        if sliced && !has_queries {
+            Err("Options --between and --iterate-field require a --query with {begin} and {end}"
+                .to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl Validation for Backup {
     fn validate(&self) -> Result<(), String> {
-        assert_dir_exists(&self.transfer.dir)
+        self.source.validate()?;
+        self.source.check_slicing(self.source.query.is_some() || self.query_file.is_some())?;
+        assert_dir_exists(&self.dir)
+    }
+}
+
+impl Validation for Copy {
+    fn validate(&self) -> Result<(), String> {
+        assert_dead_letter_exists(&self.dead_letter)?;
+        self.source.validate()?;
+        self.source.check_slicing(self.source.query.is_some())
     }
 }
 
 impl Validation for Restore {
     fn validate(&self) -> Result<(), String> {
-        assert_dir_exists(&self.transfer.dir)
+        if self.dry_run
+            && (self.apply_schema || self.create_core.is_some() || self.create_collection.is_some())
+        {
+            return Err("Options --apply-schema, --create-core and --create-collection cannot be \
+                        used together with --dry-run"
+                .to_string());
+        }
+        if self.batch_docs == 0 {
+            return Err("Option --batch-docs must be greater than zero".to_string());
+        }
+        assert_dead_letter_exists(&self.dead_letter)?;
+        assert_dir_exists(&self.dir)
     }
 }
 
-fn assert_dir_exists(dir: &PathBuf) -> Result<(), String> {
+fn assert_dead_letter_exists(dead_letter: &Option<PathBuf>) -> Result<(), String> {
+    match dead_letter {
+        Some(dir) if !dir.exists() => {
+            Err(format!("Missing folder for storing the dead-letter archives: {:?}", dir))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn assert_dir_exists(dir: &Path) -> Result<(), String> {
     if !dir.exists() {
         Err(format!("Missing folder of zip backup files: {:?}", dir))
     } else {
@@ -577,6 +1071,7 @@ fn assert_dir_exists(dir: &PathBuf) -> Result<(), String> {
 
 // region Order By
 
+#[derive(Clone)]
 pub enum SortDirection {
     Asc,
     Desc,
@@ -595,6 +1090,7 @@ impl fmt::Debug for SortDirection {
     }
 }
 
+#[derive(Clone)]
 pub struct SortField {
     pub field: String,
     pub direction: SortDirection,
@@ -647,17 +1143,17 @@ pub mod tests {
 
     // region Mockup
 
-    use crate::args::{parse_millis, parse_quantity, Arguments, CommitMode};
+    use crate::args::{
+        parse_header, parse_key_value, parse_millis, parse_quantity, parse_solr_url, Arguments,
+        CommitMode, Secret,
+    };
 
     use structopt::StructOpt;
 
     impl Arguments {
         pub fn mockup_from(argument_list: &[&str]) {
-            match Self::from_iter_safe(argument_list) {
-                Ok(_) => {
-                    panic!("Error parsing command line arguments: {}", argument_list.join(" "))
-                }
-                Err(_) => (),
+            if Self::from_iter_safe(argument_list).is_ok() {
+                panic!("Error parsing command line arguments: {}", argument_list.join(" "))
             }
         }
 
@@ -665,6 +1161,11 @@ pub mod tests {
             Self::from_iter(TEST_ARGS_BACKUP)
         }
 
+        /// The backup arguments used in tests with some more switches appended
+        pub fn mockup_args_backup_with(switches: &[&str]) -> Self {
+            Self::from_iter(TEST_ARGS_BACKUP.iter().chain(switches))
+        }
+
         pub fn mockup_args_restore() -> Self {
             Self::from_iter(TEST_ARGS_RESTORE)
         }
@@ -674,17 +1175,17 @@ pub mod tests {
         }
     }
 
-    pub const TEST_SELECT_FIELDS: &'static str = "id,date,vehiclePlate";
+    pub const TEST_SELECT_FIELDS: &str = "id,date,vehiclePlate";
 
-    const TEST_ARGS_HELP: &'static [&'static str] = &["solrcopy", "--help"];
+    const TEST_ARGS_HELP: &[&str] = &["solrcopy", "--help"];
 
-    const TEST_ARGS_VERSION: &'static [&'static str] = &["solrcopy", "--version"];
+    const TEST_ARGS_VERSION: &[&str] = &["solrcopy", "--version"];
 
-    const TEST_ARGS_HELP_BACKUP: &'static [&'static str] = &["solrcopy", "help", "backup"];
+    const TEST_ARGS_HELP_BACKUP: &[&str] = &["solrcopy", "help", "backup"];
 
-    const TEST_ARGS_HELP_RESTORE: &'static [&'static str] = &["solrcopy", "help", "restore"];
+    const TEST_ARGS_HELP_RESTORE: &[&str] = &["solrcopy", "help", "restore"];
 
-    const TEST_ARGS_BACKUP: &'static [&'static str] = &[
+    const TEST_ARGS_BACKUP: &[&str] = &[
         "solrcopy",
         "backup",
         "--url",
@@ -730,7 +1231,7 @@ pub mod tests {
         "/tmp/test.log",
     ];
 
-    const TEST_ARGS_RESTORE: &'static [&'static str] = &[
+    const TEST_ARGS_RESTORE: &[&str] = &[
         "solrcopy",
         "restore",
         "--url",
@@ -749,7 +1250,7 @@ pub mod tests {
         "debug",
     ];
 
-    const TEST_ARGS_COMMIT: &'static [&'static str] = &[
+    const TEST_ARGS_COMMIT: &[&str] = &[
         "solrcopy",
         "commit",
         "--url",
@@ -769,15 +1270,15 @@ pub mod tests {
             Arguments::Backup(get) => {
                 assert_eq!(get.options.url, TEST_ARGS_BACKUP[3]);
                 assert_eq!(get.options.core, TEST_ARGS_BACKUP[5]);
-                assert_eq!(get.transfer.dir.to_str().unwrap(), TEST_ARGS_BACKUP[7]);
-                assert_eq!(get.query, Some(TEST_ARGS_BACKUP[9].to_string()));
-                assert_eq!(get.skip, 3);
-                assert_eq!(get.limit, Some(42));
-                assert_eq!(get.num_docs, 5);
+                assert_eq!(get.dir.to_str().unwrap(), TEST_ARGS_BACKUP[7]);
+                assert_eq!(get.source.query, Some(TEST_ARGS_BACKUP[9].to_string()));
+                assert_eq!(get.source.skip, 3);
+                assert_eq!(get.source.limit, Some(42));
+                assert_eq!(get.source.num_docs, 5);
                 assert_eq!(get.archive_files, 6);
                 assert_eq!(get.transfer.readers, 7);
                 assert_eq!(get.transfer.writers, 9);
-                assert_eq!(get.options.log_level, "debug");
+                assert_eq!(get.options.logging.log_level, "debug");
             }
             _ => panic!("command must be 'backup' !"),
         };
@@ -789,17 +1290,24 @@ pub mod tests {
         match parsed {
             Arguments::Restore(put) => {
                 assert_eq!(put.options.url, TEST_ARGS_RESTORE[3]);
-                assert_eq!(put.transfer.dir.to_str().unwrap(), TEST_ARGS_RESTORE[5]);
+                assert_eq!(put.dir.to_str().unwrap(), TEST_ARGS_RESTORE[5]);
                 assert_eq!(put.options.core, TEST_ARGS_RESTORE[7]);
                 assert_eq!(put.search.unwrap(), TEST_ARGS_RESTORE[9]);
                 assert_eq!(put.flush, CommitMode::Soft);
                 assert_eq!(put.flush.as_param("?"), "?softCommit=true");
-                assert_eq!(put.options.log_level, "debug");
+                assert_eq!(put.options.logging.log_level, "debug");
             }
             _ => panic!("command must be 'restore' !"),
         };
     }
 
+    #[test]
+    fn check_restore_dry_run_conflicts() {
+        let switches = ["--dry-run", "--apply-schema"];
+        let parsed = Arguments::from_iter(TEST_ARGS_RESTORE.iter().chain(&switches));
+        assert!(parsed.validate().unwrap_err().contains("--dry-run"));
+    }
+
     #[test]
     fn check_params_commit() {
         let parsed = Arguments::mockup_args_commit();
@@ -807,7 +1315,7 @@ pub mod tests {
             Arguments::Commit(put) => {
                 assert_eq!(put.options.url, TEST_ARGS_COMMIT[3]);
                 assert_eq!(put.options.core, TEST_ARGS_COMMIT[5]);
-                assert_eq!(put.options.log_level, "debug");
+                assert_eq!(put.options.logging.log_level, "debug");
             }
             _ => panic!("command must be 'commit' !"),
         };
@@ -855,6 +1363,35 @@ pub mod tests {
         assert_eq!(parse_millis("9 minutes"), Ok(540_000));
         assert_eq!(parse_millis("10h"), Ok(36_000_000));
     }
+
+    #[test]
+    fn check_parse_key_value() {
+        let pair = |k: &str, v: &str| Ok((k.to_string(), v.to_string()));
+        assert_eq!(parse_key_value("id={value}_v2"), pair("id", "{value}_v2"));
+        assert_eq!(parse_key_value("q=a=b"), pair("q", "a=b"));
+        assert_eq!(parse_key_value("empty="), pair("empty", ""));
+        assert!(parse_key_value("=value").is_err());
+        assert!(parse_key_value("novalue").is_err());
+    }
+
+    #[test]
+    fn check_parse_solr_url() {
+        let url = |src: &str| parse_solr_url(src).unwrap();
+        assert_eq!(url("localhost:8983/solr"), "http://localhost:8983/solr");
+        assert_eq!(url("https://solr.internal:8984/solr"), "https://solr.internal:8984/solr");
+        assert!(parse_solr_url("ftp://localhost:8983/solr").is_err());
+        assert!(parse_solr_url("http://localhost:8983/solr/core").is_err());
+    }
+
+    #[test]
+    fn check_parse_header() {
+        let (name, value) = parse_header("X-Api-Key: abc:123").unwrap();
+        assert_eq!(name, "X-Api-Key");
+        assert_eq!(value, Secret::from("abc:123"));
+        assert_eq!(format!("{:?}", value), "Secret(***)");
+        assert!(parse_header("X Api: abc").is_err());
+        assert!(parse_header("novalue").is_err());
+    }
 }
 
 // end of file
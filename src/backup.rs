@@ -1,19 +1,23 @@
 use crossbeam_channel::{bounded, Receiver, Sender};
 use crossbeam_utils::thread;
-use log::{debug, error, info, trace};
+use glob::glob;
+use log::{debug, error, info, trace, warn};
 
 use std::sync::{atomic::AtomicBool, Arc};
-use std::{path::PathBuf, time::Instant};
+use std::{collections::HashSet, fs, path::Path, time::Instant};
 
 use crate::{
     args::Backup,
     bars::*,
     connection::SolrClient,
+    export::ExportReader,
     fails::*,
     helpers::*,
+    journal::Journal,
+    manifest::Manifest,
     save::Archiver,
     state::*,
-    steps::{Documents, Requests, Slices, SolrCore, Step},
+    steps::{interleave, Documents, Paging, Requests, Slices, SolrCore, Step},
 };
 
 pub(crate) fn backup_main(params: Backup) -> BoxedError {
@@ -21,19 +25,57 @@ pub(crate) fn backup_main(params: Backup) -> BoxedError {
 
     wait_with_progress(params.transfer.delay_before, "Waiting before processing...");
 
-    let slices = params.get_slices();
-    let schema = params.inspect_core()?;
+    match &params.query_file {
+        None => backup_query(&params),
+        Some(path) => {
+            let queries = params.split_by_queries(path)?;
+            info!("Backing up {} queries read from {:?}", queries.len(), path);
+            for labelled in &queries {
+                info!(
+                    "Backing up query {}: {:?}",
+                    labelled.label.as_deref().unwrap_or_default(),
+                    labelled.source.query
+                );
+                backup_query(labelled)?;
+            }
+            Ok(())
+        }
+    }
+}
 
-    let end_limit = params.get_docs_to_retrieve(&schema);
-    let num_retrieving = params.estimate_docs_quantity(&schema, &slices)?;
+fn backup_query(params: &Backup) -> BoxedError {
+    let querying = params.querying();
+    let slices = querying.plan_slices()?;
+    let schema = querying.inspect_core()?;
+    if params.source.export {
+        querying.check_export_fields(&schema)?;
+    }
+
+    let end_limit = querying.get_docs_to_retrieve(&schema);
+    let requests = querying.plan_requests(&schema)?;
+    let num_retrieving = querying.estimate_docs_quantity(&requests, &slices)?;
     let num_found = schema.num_found.to_u64();
-    let must_match = if params.workaround_shards > 0 { num_found } else { 0 };
+    let must_match = if params.source.workaround_shards > 0 { num_found } else { 0 };
     let mut retrieved = 0;
+    let mut broken = false;
+
+    let prefix = params.get_archive_prefix()?;
+    if params.with_schema {
+        params.capture_schema(&prefix)?;
+    }
+    let journal_path = params.get_journal_path(&prefix);
+    let journal = Arc::new(Journal::open(&journal_path, params.resume)?);
+    if params.resume {
+        info!("Resuming backup {} skipping {} steps already archived.", prefix, journal.len());
+        params.discard_unfinished_archives(&prefix, &journal)?;
+    }
+    let manifest_path = params.get_manifest_path(&prefix);
+    let mut manifest = params.start_manifest(&manifest_path, &schema)?;
 
     info!(
         "retrieving {} documents in the range {} to {} from {} documents of solr core {}.",
         num_retrieving,
-        params.skip + 1,
+        params.source.skip + 1,
         end_limit,
         num_found,
         params.options.core
@@ -43,7 +85,7 @@ pub(crate) fn backup_main(params: Backup) -> BoxedError {
     let started = Instant::now();
 
     thread::scope(|pool| {
-        let requests = params.get_steps(&schema);
+        let manifest: &Manifest = &manifest;
         let transfer = &params.transfer;
 
         let readers_channel = transfer.readers * 4;
@@ -53,11 +95,13 @@ pub(crate) fn backup_main(params: Backup) -> BoxedError {
         let (sender, receiver) = bounded::<Documents>(writers_channel);
         let (progress, reporter) = bounded::<u64>(transfer.writers);
 
+        let completed = Some(Arc::clone(&journal));
         pool.spawn(|_| {
-            start_querying_core(requests, slices, generator, &ctrl_c);
+            start_querying_core(requests, slices, generator, &ctrl_c, completed);
             debug!("Finished generator thread");
         });
 
+        let mut readers = Vec::with_capacity(transfer.readers);
         for ir in 0..transfer.readers {
             let producer = sender.clone();
             let iterator = sequence.clone();
@@ -66,33 +110,41 @@ pub(crate) fn backup_main(params: Backup) -> BoxedError {
             let delay = params.transfer.delay_per_request;
 
             let thread_name = format!("Reader_{}", reader);
-            pool.builder()
+            let handle = pool
+                .builder()
                 .name(thread_name)
                 .spawn(move |_| {
-                    start_retrieving_docs(reader, iterator, producer, must_match, merr, delay);
+                    let res =
+                        start_retrieving_docs(reader, iterator, producer, must_match, merr, delay);
                     debug!("Finished reader #{}", reader);
+                    res
                 })
                 .unwrap();
+            readers.push(handle);
         }
         drop(sequence);
         drop(sender);
 
-        let output_pat = params.get_archive_pattern(schema.num_found);
+        let output_pat = params.get_archive_pattern(&prefix, schema.num_found);
 
         for iw in 0..transfer.writers {
             let consumer = receiver.clone();
             let updater = progress.clone();
 
-            let dir = params.transfer.dir.clone();
+            let dir = params.dir.clone();
             let name = output_pat.clone();
             let max = params.archive_files;
+            let format = params.format;
+            let recorder = Arc::clone(&journal);
 
             let writer = iw;
             let thread_name = format!("Writer_{}", writer);
             pool.builder()
                 .name(thread_name)
                 .spawn(move |_| {
-                    start_storing_docs(writer, dir, name, max, consumer, updater);
+                    let archiver = Archiver::write_on(&dir, &name, max, recorder, manifest)
+                        .with_format(format);
+                    start_storing_docs(writer, archiver, consumer, updater);
                     debug!("Finished writer #{}", writer);
                 })
                 .unwrap();
@@ -100,13 +152,25 @@ pub(crate) fn backup_main(params: Backup) -> BoxedError {
         drop(receiver);
         drop(progress);
 
-        retrieved =
-            foreach_progress(reporter, num_retrieving, params.num_docs, params.options.is_quiet());
+        retrieved = foreach_progress(
+            reporter,
+            num_retrieving,
+            params.source.num_docs,
+            params.options.is_quiet(),
+        );
+        broken = readers.into_iter().any(|handle| handle.join().unwrap_or(true));
     })
     .unwrap();
 
+    manifest.save(&manifest_path)?;
+
     if ctrl_c.aborted() {
         raise("# Execution aborted by user!")
+    } else if broken {
+        throw(format!(
+            "Backup of core {} is incomplete: the pages of some cursor or export could not be retrieved. Use --resume for retrying them.",
+            params.options.core
+        ))
     } else {
         info!(
             "Dowloaded {} of {} documents in {:?}.",
@@ -121,17 +185,96 @@ pub(crate) fn backup_main(params: Backup) -> BoxedError {
     }
 }
 
+// region Resume
+
+impl Backup {
+    /// Removes the archives left unfinished by a interrupted backup as their steps are fetched again
+    fn discard_unfinished_archives(&self, prefix: &str, journal: &Journal) -> BoxedResult<()> {
+        // the number of docs in the names of the archives may have changed since then
+        let pattern = self.dir.join(format!("{}_docs_*_seq_*.zip", prefix));
+        let wilcard = pattern.to_string_lossy();
+        for archive in glob(&wilcard)?.filter_map(Result::ok) {
+            let name = get_filename(&archive).unwrap_or_default();
+            if !journal.has_archive(&name) {
+                warn!("Removing archive not finished in the previous execution: {:?}", archive);
+                fs::remove_file(&archive)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// endregion
+
+// region Query file
+
+impl Backup {
+    /// One backup for each query in the `--query-file` labelled for naming its archives
+    fn split_by_queries(&self, path: &Path) -> BoxedResult<Vec<Backup>> {
+        let text = fs::read_to_string(path)
+            .or_else(|cause| throw(format!("Cannot read queries in {:?}: {}", path, cause)))?;
+        let queries = parse_query_lines(&text)
+            .or_else(|cause| throw(format!("Invalid queries in {:?}: {}", path, cause)))?;
+        let res = queries
+            .into_iter()
+            .map(|(label, query)| {
+                let mut labelled = self.clone();
+                labelled.source.query = Some(query);
+                labelled.query_file = None;
+                labelled.label = Some(label);
+                labelled
+            })
+            .collect();
+        Ok(res)
+    }
+}
+
+/// Parses lines like `label<tab>query` or just `query` labelled by the line number
+fn parse_query_lines(text: &str) -> BoxedResult<Vec<(String, String)>> {
+    let mut res = Vec::new();
+    let mut labels = HashSet::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (label, query) = match line.split_once('\t') {
+            Some((label, query)) => (label.trim().to_string(), query.trim()),
+            None => (format!("query{}", num + 1), line),
+        };
+        if label.is_empty()
+            || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return throw(format!("Wrong label '{}' in line {}", label, num + 1));
+        }
+        if !labels.insert(label.clone()) {
+            return throw(format!("Repeated label '{}' in line {}", label, num + 1));
+        }
+        res.push((label, query.to_string()));
+    }
+    if res.is_empty() {
+        return raise("Found no queries");
+    }
+    Ok(res)
+}
+
+// endregion
+
 // region Channels
 
-fn start_querying_core(
-    requests: Requests, slices: Slices<String>, generator: Sender<Step>, ctrl_c: &Arc<AtomicBool>,
+pub(crate) fn start_querying_core(
+    requests: Vec<Requests>, slices: Slices<String>, generator: Sender<Step>,
+    ctrl_c: &Arc<AtomicBool>, journal: Option<Arc<Journal>>,
 ) {
     let parts = slices.get_iterator();
 
     'outer: for range in parts {
-        let docs = requests.clone();
-        for step in docs {
+        for step in interleave(&requests) {
             let filtered = range.filter(step);
+            if journal.as_ref().is_some_and(|done| done.is_done(&filtered)) {
+                trace!("Skipping step already archived: {}", filtered.url);
+                continue;
+            }
             let status = generator.send(filtered);
             if status.is_err() || ctrl_c.aborted() {
                 break 'outer;
@@ -141,21 +284,76 @@ fn start_querying_core(
     drop(generator);
 }
 
-fn start_retrieving_docs(
-    reader: usize, iterator: Receiver<Step>, producer: Sender<Documents>, must_match: u64,
+/// Times a page of a cursor is retried before abandoning the remaining pages of its step
+const CURSOR_RETRIES: usize = 3;
+
+/// Retrieves the docs of each step sending them as `Documents` for archiving or as `Docs` for copying.
+/// Returns true when the pages of a cursor or export could not be retrieved and its docs are incomplete.
+pub(crate) fn start_retrieving_docs<T: From<Documents>>(
+    reader: usize, iterator: Receiver<Step>, producer: Sender<T>, must_match: u64,
     max_errors: usize, delay: usize,
-) {
+) -> bool {
     let ctrl_c = monitor_term_sinal();
     let mut error_count = 0;
+    let mut retries = 0;
+    let mut broken = false;
 
     let mut client = SolrClient::new();
+    let mut next_page: Option<Step> = None;
     loop {
-        let received = iterator.recv();
+        // when paging with cursorMark keep following the pages before getting a new step
+        let received = match next_page.take() {
+            Some(page) => Ok(page),
+            None => iterator.recv(),
+        };
         if ctrl_c.aborted() {
             break;
         }
         let failed = match received {
-            Ok(step) => retrieve_docs_from_solr(reader, &producer, step, &mut client, must_match),
+            Ok(step) => {
+                // a failed page of a cursor or export cannot be skipped without losing the pages after it
+                let page = match step.paging {
+                    Paging::Offset => None,
+                    _ => Some(step.clone()),
+                };
+                // the pages of a export already sent would be archived twice when retrying it
+                let retried = matches!(step.paging, Paging::Cursor { .. });
+                let retrieved = match step.paging {
+                    Paging::Export { limit, num_docs } => {
+                        export_docs_from_solr(reader, &producer, step, &mut client, limit, num_docs)
+                    }
+                    _ => retrieve_docs_from_solr(reader, &producer, step, &mut client, must_match),
+                };
+                match (retrieved, page) {
+                    (Ok(next), _) => {
+                        next_page = next;
+                        retries = 0;
+                        false
+                    }
+                    (Err(_), Some(page))
+                        if retried && retries < CURSOR_RETRIES && !ctrl_c.aborted() =>
+                    {
+                        retries += 1;
+                        warn!(
+                            "Retrying #{} in thread #{} the cursor page: {}",
+                            retries, reader, page.curr
+                        );
+                        wait(retries);
+                        next_page = Some(page);
+                        false
+                    }
+                    (Err(_), Some(page)) => {
+                        error!(
+                            "Error in thread #{} retrieving the page at {}. Dropping the remaining pages of: {}",
+                            reader, page.curr, page.url
+                        );
+                        retries = 0;
+                        broken = true;
+                        false
+                    }
+                    (Err(_), None) => true,
+                }
+            }
             Err(_) => true,
         };
         if failed {
@@ -172,33 +370,66 @@ fn start_retrieving_docs(
         }
     }
     drop(producer);
+    broken
 }
 
-fn retrieve_docs_from_solr(
-    reader: usize, producer: &Sender<Documents>, step: Step, client: &mut SolrClient,
-    must_match: u64,
-) -> bool {
-    let query_url = step.url.as_str();
-    let response = fetch_docs_from_solr(reader, client, query_url, must_match);
-    match response {
-        Err(_) => true,
-        Ok(content) => {
-            let parsed = SolrCore::parse_docs_from_query(&content);
-            match parsed {
-                None => {
-                    error!("Error in thread #{} parsing from solr query: {}", reader, query_url);
-                    true
-                }
-                Some(json) => {
-                    let docs = Documents { step, docs: json.to_string() };
-                    let status = producer.send(docs);
-                    status.is_err()
-                }
+fn retrieve_docs_from_solr<T: From<Documents>>(
+    reader: usize, producer: &Sender<T>, step: Step, client: &mut SolrClient, must_match: u64,
+) -> Result<Option<Step>, ()> {
+    let query_url = step.get_query_url();
+    let content = fetch_docs_from_solr(reader, client, &query_url, must_match)?;
+    let parsed = SolrCore::parse_query_response(&content);
+    match parsed {
+        Err(cause) => {
+            error!("Error in thread #{} parsing from solr query {}: {}", reader, query_url, cause);
+            Err(())
+        }
+        Ok(result) => {
+            let json = result.docs_to_json();
+            let count = result.response.docs.len();
+            let next = result.next_cursor_mark.and_then(|mark| step.next_page(mark));
+            let docs = Documents { step, docs: json, count, last_page: next.is_none() };
+            let status = producer.send(T::from(docs));
+            match status {
+                Ok(_) => Ok(next),
+                Err(_) => Err(()),
             }
         }
     }
 }
 
+fn export_docs_from_solr<T: From<Documents>>(
+    reader: usize, producer: &Sender<T>, step: Step, client: &mut SolrClient, limit: usize,
+    num_docs: usize,
+) -> Result<Option<Step>, ()> {
+    let ctrl_c = monitor_term_sinal();
+    let query_url = step.get_query_url();
+    let stream = match client.get_as_stream(&query_url) {
+        Ok(body) => body,
+        Err(cause) => {
+            error!("Error in thread #{} exporting docs from solr: {}", reader, cause);
+            return Err(());
+        }
+    };
+    let mut curr = step.curr;
+    let mut stopped = false;
+    let batches =
+        ExportReader::new(num_docs, limit.saturating_sub(curr), |json, count, last_page| {
+            let docs = Documents { step: step.export_page(curr), docs: json, count, last_page };
+            curr += num_docs;
+            stopped = producer.send(T::from(docs)).is_err() || ctrl_c.aborted();
+            stopped
+        });
+    match batches.read(stream) {
+        Err(cause) => {
+            error!("Error in thread #{} parsing docs from solr export: {}", reader, cause);
+            Err(())
+        }
+        Ok(_) if stopped => Err(()),
+        Ok(_) => Ok(None),
+    }
+}
+
 fn fetch_docs_from_solr(
     reader: usize, client: &mut SolrClient, query_url: &str, must_match: u64,
 ) -> Result<String, ()> {
@@ -234,10 +465,8 @@ fn fetch_docs_from_solr(
 }
 
 fn start_storing_docs(
-    writer: usize, dir: PathBuf, name: String, max: usize, consumer: Receiver<Documents>,
-    progress: Sender<u64>,
+    writer: usize, mut archiver: Archiver<'_>, consumer: Receiver<Documents>, progress: Sender<u64>,
 ) {
-    let mut archiver = Archiver::write_on(&dir, &name, max);
     loop {
         let received = consumer.recv();
         match received {
@@ -260,4 +489,21 @@ fn start_storing_docs(
 
 // endregion
 
+#[cfg(test)]
+mod tests {
+    use crate::backup::*;
+
+    #[test]
+    fn check_parse_query_lines() {
+        let text = "# tenants\ntenant1\ttenant:1\n\ntenant:2 AND active:true\n";
+        let queries = parse_query_lines(text).unwrap();
+        assert_eq!(queries[0], ("tenant1".to_string(), "tenant:1".to_string()));
+        assert_eq!(queries[1], ("query4".to_string(), "tenant:2 AND active:true".to_string()));
+
+        assert!(parse_query_lines("a b\tq:1").is_err());
+        assert!(parse_query_lines("t\tq:1\nt\tq:2").is_err());
+        assert!(parse_query_lines("# none").is_err());
+    }
+}
+
 // end of file \\
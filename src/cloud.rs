@@ -0,0 +1,452 @@
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::{value::RawValue, Value};
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    args::CommonArgs,
+    connection::SolrClient,
+    fails::*,
+    helpers::*,
+    ingest::Docs,
+    steps::{Querying, Requests, SolrCore},
+    transform::{Document, Transformer},
+};
+
+// region Cluster status
+
+/// Shards and live nodes of a SolrCloud collection as reported by CLUSTERSTATUS
+#[derive(Debug)]
+pub struct CloudCollection {
+    pub name: String,
+    shards: BTreeMap<String, Shard>,
+    live_nodes: Vec<String>,
+    router: RouterSpec,
+}
+
+/// A shard of the collection read directly from one of its replicas
+#[derive(Debug, PartialEq)]
+pub struct ShardReplica {
+    pub shard: String,
+    /// Url of the replica core as in: `http://node:8983/solr/coll_shard1_replica_n1`
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClusterStatus {
+    cluster: Cluster,
+}
+
+#[derive(Deserialize, Debug)]
+struct Cluster {
+    collections: HashMap<String, Collection>,
+    #[serde(default)]
+    live_nodes: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Collection {
+    shards: BTreeMap<String, Shard>,
+    #[serde(default)]
+    router: RouterSpec,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct RouterSpec {
+    #[serde(default)]
+    name: String,
+    /// Field hashed instead of the uniqueKey
+    field: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Shard {
+    range: Option<String>,
+    state: String,
+    replicas: BTreeMap<String, Replica>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Replica {
+    core: String,
+    base_url: String,
+    node_name: String,
+    state: String,
+    leader: Option<String>,
+}
+
+impl Replica {
+    fn get_core_url(&self) -> String {
+        format!("{}{}", self.base_url.with_suffix("/"), self.core)
+    }
+}
+
+impl CommonArgs {
+    /// Describes the collection named as the core with the Collections API
+    pub fn get_cluster_status(&self) -> BoxedResult<CloudCollection> {
+        let url = format!(
+            "{}admin/collections?action=CLUSTERSTATUS&collection={}&wt=json",
+            self.url.with_suffix("/"),
+            self.core
+        );
+        debug!("Inspecting cluster status of collection {} at: {}", self.core, url);
+        let json = SolrClient::query_get_as_text(&url)?;
+        CloudCollection::parse(&json, &self.core)
+    }
+}
+
+impl CloudCollection {
+    pub fn parse(json: &str, collection: &str) -> BoxedResult<Self> {
+        let mut status: ClusterStatus = serde_json::from_str(json)?;
+        match status.cluster.collections.remove(collection) {
+            None => throw(format!("Collection {} not found in the cluster status", collection)),
+            Some(found) => Ok(CloudCollection {
+                name: collection.to_string(),
+                shards: found.shards,
+                live_nodes: status.cluster.live_nodes,
+                router: found.router,
+            }),
+        }
+    }
+
+    /// Chooses a active replica in a live node for each active shard.
+    /// The choice is the same for the same cluster status and spreads the shards across the nodes.
+    pub fn pick_replicas(&self) -> BoxedResult<Vec<ShardReplica>> {
+        let mut load: HashMap<&str, usize> = HashMap::new();
+        let mut res = Vec::new();
+        for (name, shard) in &self.shards {
+            if shard.state != "active" {
+                debug!("Skipping shard {} in state {}", name, shard.state);
+                continue;
+            }
+            let healthy = shard.replicas.values().filter(|replica| {
+                replica.state == "active"
+                    && (self.live_nodes.is_empty() || self.live_nodes.contains(&replica.node_name))
+            });
+            // min_by_key returns the first of the replicas in the least loaded nodes
+            let chosen = healthy.min_by_key(|replica| load.get(replica.node_name.as_str()));
+            match chosen {
+                None => throw(format!("Shard {} of {} has no active replica", name, self.name))?,
+                Some(replica) => {
+                    *load.entry(&replica.node_name).or_insert(0) += 1;
+                    res.push(ShardReplica { shard: name.clone(), url: replica.get_core_url() });
+                }
+            }
+        }
+        if res.is_empty() {
+            throw(format!("Collection {} has no active shards", self.name))?;
+        }
+        Ok(res)
+    }
+}
+
+impl ShardReplica {
+    /// Points the url of a query in the collection to this replica without distributing it.
+    /// Fails when the query is not in the collection as it would return the docs of all shards.
+    pub fn redirect(&self, query_url: &str, collection_url: &str) -> BoxedResult<String> {
+        let handler = query_url.strip_prefix(collection_url).filter(|path| path.starts_with('/'));
+        match handler {
+            Some(path) => Ok(format!("{}{}&distrib=false", self.url, path)),
+            None => throw(format!(
+                "Cannot query shard {} as the url is not in the collection {}: {}",
+                self.shard, collection_url, query_url
+            )),
+        }
+    }
+}
+
+// endregion
+
+// region Shard routing
+
+/// Groups the docs being restored by shard for sending each group straight to the shard leader
+#[derive(Debug)]
+pub struct ShardRouter {
+    field: String,
+    shards: Vec<ShardRoute>,
+    /// Update url of the collection for the docs that cannot be routed
+    default_url: String,
+}
+
+#[derive(Debug)]
+struct ShardRoute {
+    name: String,
+    min: i32,
+    max: i32,
+    update_url: String,
+}
+
+impl ShardRouter {
+    /// Discovers the hash ranges and leaders of the shards. None when the router is not `compositeId`.
+    pub fn discover(options: &CommonArgs, update_url: &str) -> BoxedResult<Option<Self>> {
+        let collection = options.get_cluster_status()?;
+        let key_url = options.get_core_handler_url("/schema/uniquekey?wt=json");
+        let unique_key = SolrCore::parse_unique_key(&SolrClient::query_get_as_text(&key_url)?)?;
+        let collection_url = format!("{}{}", options.url.with_suffix("/"), options.core);
+        Self::from_collection(&collection, &unique_key, update_url, &collection_url)
+    }
+
+    fn from_collection(
+        collection: &CloudCollection, unique_key: &str, update_url: &str, collection_url: &str,
+    ) -> BoxedResult<Option<Self>> {
+        if collection.router.name != "compositeId" {
+            info!("Not routing docs by shard with the {} router", collection.router.name);
+            return Ok(None);
+        }
+        let mut shards = Vec::new();
+        for (name, shard) in &collection.shards {
+            if shard.state != "active" {
+                continue;
+            }
+            let (min, max) = match shard.range.as_deref().and_then(parse_hash_range) {
+                Some(range) => range,
+                None => {
+                    throw(format!("Shard {} of {} has no valid hash range", name, collection.name))?
+                }
+            };
+            let leader = shard.replicas.values().find(|replica| {
+                replica.leader.as_deref() == Some("true") && replica.state == "active"
+            });
+            match leader {
+                None => {
+                    throw(format!("Shard {} of {} has no active leader", name, collection.name))?
+                }
+                Some(replica) => {
+                    let update_url =
+                        update_url.replacen(collection_url, &replica.get_core_url(), 1);
+                    debug!("Routing docs of shard {} to: {}", name, update_url);
+                    shards.push(ShardRoute { name: name.clone(), min, max, update_url });
+                }
+            }
+        }
+        let field = collection.router.field.as_deref().unwrap_or(unique_key).to_string();
+        Ok(Some(ShardRouter { field, shards, default_url: update_url.to_string() }))
+    }
+
+    /// Splits the docs by the update url of the leader of their shards keeping the order of the docs
+    pub fn group(&self, docs: &Docs, transformer: Option<&Transformer>) -> Vec<(&str, Docs)> {
+        let parsed: Vec<&RawValue> = match serde_json::from_str(&docs.json) {
+            Ok(all) => all,
+            Err(_) => return vec![(&self.default_url, docs.clone())],
+        };
+        let mut groups: Vec<(&str, Vec<&str>)> = Vec::new();
+        for raw in parsed {
+            let url = self.route(raw.get(), transformer).unwrap_or(&self.default_url);
+            match groups.iter_mut().find(|(group, _)| *group == url) {
+                Some((_, members)) => members.push(raw.get()),
+                None => groups.push((url, vec![raw.get()])),
+            }
+        }
+        let to_docs = |members: Vec<&str>| Docs {
+            json: format!("[{}]", members.join(COMMA)),
+            archive: docs.archive.clone(),
+            entry: docs.entry.clone(),
+        };
+        groups.into_iter().map(|(url, members)| (url, to_docs(members))).collect()
+    }
+
+    fn route(&self, json: &str, transformer: Option<&Transformer>) -> Option<&str> {
+        let mut doc: Document = serde_json::from_str(json).ok()?;
+        if let Some(transformer) = transformer {
+            transformer.apply_to(&mut doc);
+        }
+        let key = match doc.get(&self.field)? {
+            Value::String(text) => text.clone(),
+            Value::Number(num) => num.to_string(),
+            _ => return None,
+        };
+        let hash = composite_id_hash(&key)?;
+        let found = self.shards.iter().find(|shard| shard.min <= hash && hash <= shard.max)?;
+        Some(&found.update_url)
+    }
+
+    pub fn get_shard_names(&self) -> Vec<&str> {
+        self.shards.iter().map(|shard| shard.name.as_str()).collect()
+    }
+}
+
+/// Parses the hash range of a shard as in: `80000000-ffffffff`
+fn parse_hash_range(range: &str) -> Option<(i32, i32)> {
+    let (min, max) = range.split_once('-')?;
+    let min = u32::from_str_radix(min, 16).ok()?;
+    let max = u32::from_str_radix(max, 16).ok()?;
+    Some((min as i32, max as i32))
+}
+
+/// Hash of the `compositeId` router for ids like `doc1`, `tenant!doc1` or `tenant/8!doc1`.
+/// None for the ids with more levels, that are left for Solr to route.
+fn composite_id_hash(id: &str) -> Option<i32> {
+    let hash = match id.split_once('!') {
+        None => murmur3_x86_32(id.as_bytes(), 0),
+        Some((_, rest)) if rest.is_empty() || rest.contains('!') => return None,
+        Some((prefix, rest)) => {
+            let (route, bits) = match prefix.split_once('/') {
+                None => (prefix, 16),
+                Some((route, bits)) => (route, bits.parse::<u32>().ok()?.min(32)),
+            };
+            let mask = if bits == 0 { 0 } else { u32::MAX << (32 - bits) };
+            let upper = murmur3_x86_32(route.as_bytes(), 0) & mask;
+            let lower = murmur3_x86_32(rest.as_bytes(), 0) & !mask;
+            upper | lower
+        }
+    };
+    Some(hash as i32)
+}
+
+/// MurmurHash3 x86 32 bits as used by Solr for hashing the ids
+fn murmur3_x86_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut hash = seed;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        hash ^= mix(k);
+        hash = hash.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    if !tail.is_empty() {
+        let k = tail.iter().rev().fold(0, |acc, byte| (acc << 8) | u32::from(*byte));
+        hash ^= mix(k);
+    }
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+// endregion
+
+// region Shard requests
+
+impl Querying<'_> {
+    /// The requests for retrieving the docs of the core or, with `--by-shard`, of each shard.
+    /// The docs of each shard are numbered after the ones of the previous shards.
+    pub fn plan_requests(&self, schema: &SolrCore) -> BoxedResult<Vec<Requests>> {
+        let requests = self.get_steps(schema);
+        if !self.source.by_shard {
+            return Ok(vec![requests]);
+        }
+        let replicas = self.options.get_cluster_status()?.pick_replicas()?;
+        let collection_url = format!("{}{}", self.options.url.with_suffix("/"), self.options.core);
+        let diagnostics_url = self.get_query_for_diagnostics();
+
+        let mut offset = 0;
+        let mut res = Vec::new();
+        for replica in replicas {
+            let count_url = replica.redirect(&diagnostics_url, &collection_url)?;
+            let num_found = SolrCore::parse_num_found(&SolrClient::query_get_as_text(&count_url)?)?;
+            info!("Reading {} docs of shard {} from {}", num_found, replica.shard, replica.url);
+            res.push(Requests {
+                curr: offset,
+                limit: offset + num_found,
+                offset,
+                url: replica.redirect(&requests.url, &collection_url)?,
+                ..requests.clone()
+            });
+            offset += num_found;
+        }
+        Ok(res)
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::cloud::*;
+
+    const CLUSTER_STATUS: &str = r#"{"cluster":{
+        "collections":{"demo":{"shards":{
+            "shard1":{"range":"80000000-ffffffff","state":"active","replicas":{
+                "core_node3":{"core":"demo_shard1_replica_n1","base_url":"http://node1:8983/solr",
+                    "node_name":"node1:8983_solr","state":"active","leader":"true"},
+                "core_node5":{"core":"demo_shard1_replica_n2","base_url":"http://node2:8983/solr",
+                    "node_name":"node2:8983_solr","state":"active"}}},
+            "shard2":{"range":"0-7fffffff","state":"active","replicas":{
+                "core_node7":{"core":"demo_shard2_replica_n4","base_url":"http://node1:8983/solr",
+                    "node_name":"node1:8983_solr","state":"active","leader":"true"},
+                "core_node8":{"core":"demo_shard2_replica_n6","base_url":"http://node2:8983/solr",
+                    "node_name":"node2:8983_solr","state":"active"},
+                "core_node9":{"core":"demo_shard2_replica_n9","base_url":"http://node3:8983/solr",
+                    "node_name":"node3:8983_solr","state":"down"}}},
+            "shard3":{"range":"0-7fffffff","state":"inactive","replicas":{}}},
+            "router":{"name":"compositeId"}}},
+        "live_nodes":["node1:8983_solr","node2:8983_solr"]}}"#;
+
+    #[test]
+    fn check_pick_replicas() {
+        let collection = CloudCollection::parse(CLUSTER_STATUS, "demo").unwrap();
+        let replicas = collection.pick_replicas().unwrap();
+        assert_eq!(replicas.len(), 2);
+        assert_eq!(replicas[0].url, "http://node1:8983/solr/demo_shard1_replica_n1");
+        assert_eq!(replicas[1].url, "http://node2:8983/solr/demo_shard2_replica_n6");
+
+        let query = "http://solr:8983/solr/demo/select?wt=json&q=*:*";
+        let local = replicas[1].redirect(query, "http://solr:8983/solr/demo").unwrap();
+        assert_eq!(
+            local,
+            "http://node2:8983/solr/demo_shard2_replica_n6/select?wt=json&q=*:*&distrib=false"
+        );
+        assert!(replicas[1].redirect(query, "http://solr:8983/solr/demo/").is_err());
+        assert!(replicas[1].redirect(query, "http://solr:8983/solr/dem").is_err());
+
+        assert!(CloudCollection::parse(CLUSTER_STATUS, "other").is_err());
+    }
+
+    #[test]
+    fn check_murmur3_hash() {
+        assert_eq!(murmur3_x86_32(b"", 0), 0);
+        assert_eq!(murmur3_x86_32(b"", 1), 0x514e_28b7);
+        assert_eq!(murmur3_x86_32(b"Hello, world!", 1234), 0xfaf6_cdb3);
+        let fox = b"The quick brown fox jumps over the lazy dog";
+        assert_eq!(murmur3_x86_32(fox, 0), 0x2e4f_f723);
+
+        let tenant = murmur3_x86_32(b"tenant", 0);
+        let doc = murmur3_x86_32(b"doc1", 0);
+        let composite = (tenant & 0xffff_0000) | (doc & 0x0000_ffff);
+        assert_eq!(composite_id_hash("tenant!doc1"), Some(composite as i32));
+        assert_eq!(composite_id_hash("tenant/0!doc1"), Some(doc as i32));
+        assert_eq!(composite_id_hash("a!b!c"), None);
+        assert_eq!(parse_hash_range("80000000-ffffffff"), Some((i32::MIN, -1)));
+    }
+
+    #[test]
+    fn check_shard_router() {
+        let collection = CloudCollection::parse(CLUSTER_STATUS, "demo").unwrap();
+        let collection_url = "http://solr:8983/solr/demo";
+        let update_url = "http://solr:8983/solr/demo/update/json/docs?commit=true";
+        let router = ShardRouter::from_collection(&collection, "id", update_url, collection_url);
+        let router = router.unwrap().unwrap();
+        assert_eq!(router.get_shard_names(), vec!["shard1", "shard2"]);
+
+        let ids = ["a", "b", "c", "d", "e", "f", "a!b!c"];
+        let json = ids.iter().map(|id| format!(r#"{{"id":"{}"}}"#, id)).collect::<Vec<_>>();
+        let docs = Docs {
+            json: format!("[{}]", json.join(",")),
+            archive: "a.zip".to_string(),
+            entry: "docs_at_000000001.json".to_string(),
+        };
+        let groups = router.group(&docs, None);
+        let mut total = 0;
+        for (url, group) in &groups {
+            let parsed: Vec<Document> = serde_json::from_str(&group.json).unwrap();
+            for doc in &parsed {
+                let hash = composite_id_hash(doc["id"].as_str().unwrap());
+                let expected = match hash {
+                    None => update_url,
+                    Some(neg) if neg < 0 => "http://node1:8983/solr/demo_shard1_replica_n1/update",
+                    Some(_) => "http://node1:8983/solr/demo_shard2_replica_n4/update",
+                };
+                assert!(url.starts_with(expected));
+            }
+            total += parsed.len();
+        }
+        assert_eq!(total, ids.len());
+    }
+}
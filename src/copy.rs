@@ -0,0 +1,223 @@
+use crossbeam_channel::bounded;
+use crossbeam_utils::thread;
+use log::{debug, info};
+
+use std::time::Instant;
+
+use crate::{
+    args::{CommonArgs, Copy, ParallelArgs},
+    backup::{start_querying_core, start_retrieving_docs},
+    bars::*,
+    fails::*,
+    helpers::*,
+    ingest::Docs,
+    restore::{pre_post_processing, start_indexing_docs, Indexing, DEAD_LETTER_FILES},
+    save::DeadLetters,
+    state::*,
+    steps::{Querying, Step},
+    transform::Transformer,
+};
+
+pub(crate) fn copy_main(params: Copy) -> BoxedError {
+    debug!("# COPY {:?}", params);
+
+    let target = params.get_target();
+    let updating = params.get_target_transfer();
+    let update_url = target.get_update_docs_url(&params.flush, &updating);
+    let final_commit = !params.no_final_commit;
+    let disable_replication = params.disable_replication;
+    let transformer = Transformer::from_args(&params.transform)?;
+    let dead_letters = params
+        .dead_letter
+        .as_ref()
+        .map(|dir| DeadLetters::write_on(dir, &target.core, DEAD_LETTER_FILES));
+    let bisect = params.bisect;
+    let source = params.querying();
+
+    wait_with_progress(params.transfer.delay_before, "Waiting before processing...");
+
+    let slices = source.plan_slices()?;
+    let schema = source.inspect_core()?;
+    if params.source.export {
+        source.check_export_fields(&schema)?;
+    }
+
+    let requests = source.plan_requests(&schema)?;
+    let num_retrieving = source.estimate_docs_quantity(&requests, &slices)?;
+    let num_found = schema.num_found.to_u64();
+    let must_match = if params.source.workaround_shards > 0 { num_found } else { 0 };
+    let mut copied = 0;
+    let mut broken = false;
+
+    info!(
+        "copying {} documents from solr core {} into solr core {}.",
+        num_retrieving, params.options.core, target.core
+    );
+    debug!("Solr Update Handler: {}", update_url);
+
+    pre_post_processing(&target, disable_replication, false)?;
+
+    let indexing = Indexing::new(update_url, &updating)
+        .with_transformer(transformer)
+        .with_dead_letters(dead_letters)
+        .with_bisect(bisect);
+    let ctrl_c = monitor_term_sinal();
+    let started = Instant::now();
+
+    thread::scope(|pool| {
+        let indexing = &indexing;
+        let transfer = &params.transfer;
+
+        let readers_channel = transfer.readers * 4;
+        let writers_channel = transfer.writers * 3;
+
+        let (generator, sequence) = bounded::<Step>(readers_channel);
+        let (sender, receiver) = bounded::<Docs>(writers_channel);
+        let (progress, reporter) = bounded::<u64>(transfer.writers);
+
+        pool.spawn(|_| {
+            start_querying_core(requests, slices, generator, &ctrl_c, None);
+            debug!("Finished generator thread");
+        });
+
+        let mut readers = Vec::with_capacity(transfer.readers);
+        for ir in 0..transfer.readers {
+            let producer = sender.clone();
+            let iterator = sequence.clone();
+            let reader = ir;
+            let merr = transfer.max_errors;
+            let delay = transfer.delay_per_request;
+
+            let thread_name = format!("Reader_{}", reader);
+            let handle = pool
+                .builder()
+                .name(thread_name)
+                .spawn(move |_| {
+                    let res =
+                        start_retrieving_docs(reader, iterator, producer, must_match, merr, delay);
+                    debug!("Finished reader #{}", reader);
+                    res
+                })
+                .unwrap();
+            readers.push(handle);
+        }
+        drop(sequence);
+        drop(sender);
+
+        for iw in 0..transfer.writers {
+            let consumer = receiver.clone();
+            let updater = progress.clone();
+
+            let writer = iw;
+            let thread_name = format!("Writer_{}", writer);
+            pool.builder()
+                .name(thread_name)
+                .spawn(move |_| {
+                    start_indexing_docs(writer, consumer, updater, indexing);
+                    debug!("Finished writer #{}", writer);
+                })
+                .unwrap();
+        }
+        drop(receiver);
+        drop(progress);
+
+        let quiet = params.options.is_quiet();
+        copied = foreach_progress(reporter, num_retrieving, params.source.num_docs, quiet);
+        broken = readers.into_iter().any(|handle| handle.join().unwrap_or(true));
+    })
+    .unwrap();
+
+    pre_post_processing(&target, disable_replication, true)?;
+
+    if ctrl_c.aborted() {
+        raise("# Execution aborted by user!")
+    } else if broken {
+        throw(format!(
+            "Copy into core {} is incomplete: the pages of some cursor or export could not be retrieved.",
+            target.core
+        ))
+    } else {
+        info!("Copied {} of {} documents in {:?}.", copied, num_retrieving, started.elapsed());
+        if copied > 0 {
+            if final_commit {
+                crate::commit::commit_main(target.to_command())?;
+            }
+            wait_with_progress(params.transfer.delay_after, "Waiting after all processing...");
+        }
+        Ok(())
+    }
+}
+
+impl Copy {
+    /// Solr server and core receiving the documents
+    pub fn get_target(&self) -> CommonArgs {
+        let mut target = self.options.clone();
+        if let Some(url) = &self.into_url {
+            target.url = url.clone();
+        }
+        target.core = self.into_core.clone();
+        target
+    }
+
+    /// The settings of the updates of the target core, leaving the `--params` and errors of the source apart
+    fn get_target_transfer(&self) -> ParallelArgs {
+        let mut updating = self.transfer.clone();
+        updating.params = self.into_params.clone();
+        updating.max_errors = self.into_max_errors;
+        updating.delay_per_request = self.into_delay;
+        updating
+    }
+
+    /// The source core and query of the copy, retrieved as in a backup
+    fn querying(&self) -> Querying<'_> {
+        Querying { source: &self.source, options: &self.options, transfer: &self.transfer }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn check_copy_target() {
+        let parsed = Arguments::from_iter(&[
+            "solrcopy",
+            "copy",
+            "--url",
+            "http://source:8983/solr",
+            "--core",
+            "demo",
+            "--into-core",
+            "target",
+            "--iterate-by",
+            "range",
+            "--between",
+            "0",
+            "100",
+            "--query",
+            "id:[{begin} TO {end}]",
+            "--max-errors",
+            "3",
+            "--params",
+            "shards=shard1",
+            "--into-params",
+            "useParams=target",
+        ]);
+        match parsed {
+            Arguments::Copy(cpy) => {
+                let target = cpy.get_target();
+                assert_eq!(target.url, "http://source:8983/solr");
+                assert_eq!(target.core, "target");
+                assert_eq!(cpy.transfer.max_errors, 3);
+                assert_eq!(cpy.source.iterate_between, vec!["0", "100"]);
+
+                let updating = cpy.get_target_transfer();
+                assert_eq!(updating.max_errors, 0);
+                let update_url = target.get_update_docs_url(&cpy.flush, &updating);
+                assert!(update_url.ends_with("&useParams=target"));
+            }
+            _ => panic!("command must be 'copy' !"),
+        };
+    }
+}
@@ -0,0 +1,110 @@
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use std::collections::HashMap;
+
+use crate::{
+    args::{CommonArgs, Restore},
+    connection::SolrClient,
+    fails::*,
+    helpers::*,
+};
+
+#[derive(Deserialize, Debug)]
+struct CoreStatus {
+    status: HashMap<String, Map<String, Value>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CollectionList {
+    collections: Vec<String>,
+}
+
+/// Creates the core or the collection of `--create-core` or `--create-collection` when missing
+pub(crate) fn create_target_main(params: &Restore) -> BoxedError {
+    let options = &params.options;
+    if let Some(configset) = &params.create_core {
+        if options.core_exists()? {
+            info!("Core {} already exists.", options.core);
+        } else {
+            let url = format!(
+                "{}action=CREATE&name={}&configSet={}&wt=json",
+                options.get_admin_url("cores"),
+                options.core,
+                configset
+            );
+            info!("Creating core {} with configset {}", options.core, configset);
+            debug!("Creating core at: {}", url);
+            SolrClient::query_get_as_text(&url)?;
+        }
+    }
+    if let Some(configset) = &params.create_collection {
+        if options.collection_exists()? {
+            info!("Collection {} already exists.", options.core);
+        } else {
+            let url = format!(
+                "{}action=CREATE&name={}&collection.configName={}&numShards={}&replicationFactor={}&wt=json",
+                options.get_admin_url("collections"),
+                options.core,
+                configset,
+                params.num_shards,
+                params.replication_factor
+            );
+            info!(
+                "Creating collection {} with configset {}, {} shards and {} replicas",
+                options.core, configset, params.num_shards, params.replication_factor
+            );
+            debug!("Creating collection at: {}", url);
+            SolrClient::query_get_as_text(&url)?;
+        }
+    }
+    Ok(())
+}
+
+impl CommonArgs {
+    fn get_admin_url(&self, handler: &str) -> String {
+        format!("{}admin/{}?", self.url.with_suffix("/"), handler)
+    }
+
+    fn core_exists(&self) -> BoxedResult<bool> {
+        let url =
+            format!("{}action=STATUS&core={}&wt=json", self.get_admin_url("cores"), self.core);
+        debug!("Checking core {} at: {}", self.core, url);
+        parse_core_exists(&SolrClient::query_get_as_text(&url)?, &self.core)
+    }
+
+    fn collection_exists(&self) -> BoxedResult<bool> {
+        let url = format!("{}action=LIST&wt=json", self.get_admin_url("collections"));
+        debug!("Checking collection {} at: {}", self.core, url);
+        parse_collection_exists(&SolrClient::query_get_as_text(&url)?, &self.core)
+    }
+}
+
+/// The CoreAdmin STATUS answers a empty object for missing cores
+fn parse_core_exists(json: &str, core: &str) -> BoxedResult<bool> {
+    let parsed: CoreStatus = serde_json::from_str(json)?;
+    Ok(parsed.status.get(core).is_some_and(|status| !status.is_empty()))
+}
+
+fn parse_collection_exists(json: &str, collection: &str) -> BoxedResult<bool> {
+    let parsed: CollectionList = serde_json::from_str(json)?;
+    Ok(parsed.collections.iter().any(|name| name == collection))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::create::*;
+
+    #[test]
+    fn check_target_exists() {
+        let missing = r#"{"responseHeader":{"status":0},"initFailures":{},"status":{"demo":{}}}"#;
+        let found = r#"{"initFailures":{},"status":{"demo":{"name":"demo","instanceDir":"/var/solr/data/demo"}}}"#;
+        assert!(!parse_core_exists(missing, "demo").unwrap());
+        assert!(parse_core_exists(found, "demo").unwrap());
+
+        let collections = r#"{"responseHeader":{"status":0},"collections":["demo","other"]}"#;
+        assert!(parse_collection_exists(collections, "demo").unwrap());
+        assert!(!parse_collection_exists(collections, "new").unwrap());
+    }
+}
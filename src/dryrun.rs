@@ -0,0 +1,211 @@
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{
+    args::{CommonArgs, Restore},
+    connection::SolrClient,
+    export::SchemaField,
+    fails::*,
+    fetch::ResponseError,
+    ingest::ArchiveReader,
+    transform::Transformer,
+};
+
+/// Reads the archives and checks their docs against the schema of the target core without updating it
+pub(crate) fn dry_run_main(
+    params: &Restore, found: &[PathBuf], transformer: Option<&Transformer>,
+) -> BoxedError {
+    let schema = TargetSchema::inspect(&params.options)?;
+    debug!("Target schema: {:?}", schema);
+
+    let mut report = DryRunReport::default();
+    for archive_path in found {
+        info!("Archive {:?}", archive_path);
+        for (entry_name, entry_contents) in ArchiveReader::create_reader(archive_path)? {
+            let count =
+                report.add_entry(&schema, &entry_contents, transformer).or_else(|cause| {
+                    throw(format!("Invalid docs in {:?} {}: {}", archive_path, entry_name, cause))
+                })?;
+            info!("  {}: {} docs", entry_name, count);
+        }
+    }
+    let fields = report.fields.keys().cloned().collect::<Vec<_>>();
+    info!("Found {} docs with the fields: {}", report.docs, fields.join(", "));
+
+    let problems = report.check(&schema);
+    if problems.is_empty() {
+        info!("The archives match the schema of core {}.", params.options.core);
+        Ok(())
+    } else {
+        for problem in &problems {
+            warn!("  {}", problem);
+        }
+        error!(
+            "Dry run found {} problems restoring into core {}.",
+            problems.len(),
+            params.options.core
+        );
+        throw(format!("The archives don't match the schema of core {}.", params.options.core))
+    }
+}
+
+// region Schema
+
+#[derive(Deserialize, Debug)]
+struct TargetKey {
+    #[serde(rename = "uniqueKey", default)]
+    unique_key: Option<String>,
+}
+
+/// Fields of the target core as described by the Solr Schema API
+#[derive(Debug, Default)]
+pub struct TargetSchema {
+    fields: Vec<SchemaField>,
+    dynamic: Vec<SchemaField>,
+    required: Vec<String>,
+    unique_key: Option<String>,
+}
+
+impl TargetSchema {
+    pub fn inspect(options: &CommonArgs) -> BoxedResult<Self> {
+        let fields_url = options.get_core_handler_url("/schema/fields?wt=json&showDefaults=true");
+        debug!("Inspecting fields of core {} at: {}", options.core, fields_url);
+        let fields = SolrClient::query_get_as_text(&fields_url)?;
+
+        let dynamic_url =
+            options.get_core_handler_url("/schema/dynamicfields?showDefaults=true&wt=json");
+        let dynamic = SolrClient::query_get_as_text(&dynamic_url)?;
+
+        let key_url = options.get_core_handler_url("/schema/uniquekey?wt=json");
+        let unique_key = SolrClient::query_get_as_text(&key_url)?;
+
+        let mut schema = Self::parse(&fields, &dynamic)?;
+        schema.unique_key = Self::parse_unique_key(&unique_key)?;
+        Ok(schema)
+    }
+
+    /// Parses the response of `/schema/uniquekey` where a schema without uniqueKey has it null
+    fn parse_unique_key(json: &str) -> Result<Option<String>, ResponseError> {
+        let parsed: TargetKey =
+            serde_json::from_str(json).map_err(|cause| ResponseError::from_json(cause, json))?;
+        Ok(parsed.unique_key)
+    }
+
+    /// Parses the responses of `/schema/fields` and `/schema/dynamicfields`
+    fn parse(fields_json: &str, dynamic_json: &str) -> BoxedResult<Self> {
+        let fields = SchemaField::parse_schema_fields(fields_json)?;
+        let dynamic = SchemaField::parse_schema_fields(dynamic_json)?;
+        let required = fields
+            .iter()
+            .filter(|field| field.required && field.default.is_none())
+            .map(|field| field.name.clone())
+            .collect();
+        Ok(TargetSchema { fields, dynamic, required, unique_key: None })
+    }
+
+    /// True when the field is declared or matches a dynamic field
+    fn knows(&self, name: &str) -> bool {
+        self.fields.iter().any(|field| field.name == name)
+            || self.dynamic.iter().any(|field| field.matches(name))
+    }
+
+    /// Fields that every doc must have for being accepted by the core
+    fn get_required(&self) -> impl Iterator<Item = &String> {
+        self.required
+            .iter()
+            .chain(self.unique_key.iter().filter(move |key| !self.required.contains(key)))
+    }
+}
+
+// endregion
+
+// region Report
+
+#[derive(Debug, Default)]
+struct DryRunReport {
+    docs: usize,
+    /// count of docs having each field
+    fields: BTreeMap<String, usize>,
+    /// count of docs lacking each required field
+    missing: BTreeMap<String, usize>,
+}
+
+impl DryRunReport {
+    /// Collects the fields of the docs in the entry returning how many docs it has
+    fn add_entry(
+        &mut self, schema: &TargetSchema, json: &str, transformer: Option<&Transformer>,
+    ) -> Result<usize, serde_json::Error> {
+        let docs: Vec<Map<String, Value>> = match transformer {
+            None => serde_json::from_str(json)?,
+            Some(transformer) => serde_json::from_str(&transformer.apply(json)?)?,
+        };
+        for doc in &docs {
+            for field in doc.keys() {
+                *self.fields.entry(field.clone()).or_insert(0) += 1;
+            }
+            for field in schema.get_required() {
+                if !doc.contains_key(field) {
+                    *self.missing.entry(field.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+        self.docs += docs.len();
+        Ok(docs.len())
+    }
+
+    fn check(&self, schema: &TargetSchema) -> Vec<String> {
+        let mut problems = Vec::new();
+        if schema.unique_key.is_none() {
+            problems.push("the target core has no uniqueKey".to_string());
+        }
+        for (field, count) in &self.fields {
+            if !field.starts_with('_') && !schema.knows(field) {
+                problems.push(format!("unknown field {} found in {} docs", field, count));
+            }
+        }
+        for (field, count) in &self.missing {
+            problems.push(format!("required field {} missing in {} docs", field, count));
+        }
+        problems
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::dryrun::*;
+
+    const FIELDS: &str = r#"{"fields":[
+        {"name":"id","type":"string","required":true},
+        {"name":"name","type":"text_general"},
+        {"name":"kind","type":"string","required":true,"default":"none"},
+        {"name":"price","type":"pfloat","required":true}]}"#;
+
+    const DYNAMIC: &str = r#"{"dynamicFields":[{"name":"*_s","type":"string"},{"name":"attr_*","type":"text_general"}]}"#;
+
+    #[test]
+    fn check_dry_run_report() {
+        let mut schema = TargetSchema::parse(FIELDS, DYNAMIC).unwrap();
+        assert!(schema.knows("name") && schema.knows("color_s") && schema.knows("attr_size"));
+        assert!(!schema.knows("color"));
+
+        let docs = r#"[{"id":"1","name":"a","price":1.0,"color_s":"red"},{"name":"b","color":"blue","_version_":1}]"#;
+        let mut report = DryRunReport::default();
+        assert_eq!(report.add_entry(&schema, docs, None).unwrap(), 2);
+        assert_eq!(report.fields["name"], 2);
+
+        let problems = report.check(&schema);
+        assert_eq!(problems.len(), 4);
+        assert!(problems.contains(&"unknown field color found in 1 docs".to_string()));
+        assert!(problems.contains(&"required field id missing in 1 docs".to_string()));
+
+        schema.unique_key = TargetSchema::parse_unique_key(r#"{"uniqueKey":"id"}"#).unwrap();
+        assert_eq!(report.check(&schema).len(), 3);
+        assert_eq!(TargetSchema::parse_unique_key(r#"{"uniqueKey":null}"#).unwrap(), None);
+        assert!(TargetSchema::parse_unique_key("<html>401 Unauthorized</html>").is_err());
+    }
+}
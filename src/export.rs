@@ -0,0 +1,357 @@
+use log::debug;
+use serde::de::{
+    DeserializeSeed, Deserializer, Error as _, IgnoredAny, MapAccess, SeqAccess, Visitor,
+};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use std::fmt;
+use std::io::{BufReader, Read};
+
+use crate::{
+    connection::SolrClient,
+    fails::*,
+    fetch::ResponseError,
+    helpers::*,
+    steps::{Querying, SolrCore},
+};
+
+// region Export eligibility
+
+#[derive(Deserialize, Debug)]
+pub struct SchemaField {
+    pub name: String,
+    #[serde(rename = "docValues", default)]
+    pub doc_values: bool,
+    #[serde(default)]
+    pub required: bool,
+    pub default: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SchemaFields {
+    #[serde(alias = "dynamicFields")]
+    fields: Vec<SchemaField>,
+}
+
+impl Querying<'_> {
+    /// Checks if all fields selected and sorted have docValues as required by the /export handler
+    pub fn check_export_fields(&self, schema: &SolrCore) -> BoxedResult<()> {
+        let fields_url =
+            self.options.get_core_handler_url("/schema/fields?showDefaults=true&wt=json");
+        let dynamic_url =
+            self.options.get_core_handler_url("/schema/dynamicfields?showDefaults=true&wt=json");
+        debug!("Inspecting docValues of core {} at: {}", self.options.core, fields_url);
+
+        let fields =
+            SchemaField::parse_schema_fields(&SolrClient::query_get_as_text(&fields_url)?)?;
+        let dynamic =
+            SchemaField::parse_schema_fields(&SolrClient::query_get_as_text(&dynamic_url)?)?;
+
+        let mut wanted: Vec<&str> = schema.fields.iter().map(String::as_str).collect();
+        wanted.extend(self.source.order.iter().map(|sf| sf.field.as_str()));
+        if let Some(key) = &schema.unique_key {
+            wanted.push(key);
+        }
+        wanted.sort_unstable();
+        wanted.dedup();
+
+        let ineligible: Vec<&str> = wanted
+            .into_iter()
+            .filter(|name| !SchemaField::has_doc_values(name, &fields, &dynamic))
+            .collect();
+        if ineligible.is_empty() {
+            Ok(())
+        } else {
+            throw(format!(
+                "Fields without docValues cannot be used with --export: {}\n note: use --select \
+                 and --order with fields having docValues only",
+                ineligible.join(COMMA)
+            ))
+        }
+    }
+}
+
+impl SchemaField {
+    /// Parses the field list from the Schema API: `/schema/fields?showDefaults=true`
+    pub fn parse_schema_fields(json: &str) -> Result<Vec<SchemaField>, ResponseError> {
+        let parsed: SchemaFields =
+            serde_json::from_str(json).map_err(|cause| ResponseError::from_json(cause, json))?;
+        Ok(parsed.fields)
+    }
+
+    fn has_doc_values(name: &str, fields: &[SchemaField], dynamic: &[SchemaField]) -> bool {
+        if let Some(found) = fields.iter().find(|field| field.name == name) {
+            return found.doc_values;
+        }
+        let matching = dynamic.iter().find(|field| field.matches(name));
+        matching.is_some_and(|field| field.doc_values)
+    }
+
+    /// True when the name is the field or matches a dynamic field like `*_s` or `attr_*`
+    pub fn matches(&self, name: &str) -> bool {
+        if let Some(suffix) = self.name.strip_prefix('*') {
+            name.ends_with(suffix)
+        } else if let Some(prefix) = self.name.strip_suffix('*') {
+            name.starts_with(prefix)
+        } else {
+            self.name == name
+        }
+    }
+}
+
+// endregion
+
+// region Export streaming
+
+/// Splits the docs streamed by the /export handler in json arrays of `num_docs` documents,
+/// parsing the `response.docs` of the envelope as the documents arrive
+pub struct ExportReader<F> {
+    num_docs: usize,
+    remaining: usize,
+    handle: F,
+    batch: Vec<Box<RawValue>>,
+    pending: Option<(String, usize)>,
+    found: bool,
+    stopped: bool,
+}
+
+/// The keys leading to the docs array in the /export response
+const DOCS_PATH: &[&str] = &["response", "docs"];
+
+impl<F> ExportReader<F>
+where
+    F: FnMut(String, usize, bool) -> bool,
+{
+    /// The `handle` gets each json array with the number of documents in it and whether it is
+    /// the last one. It returns true for stopping.
+    pub fn new(num_docs: usize, limit: usize, handle: F) -> Self {
+        ExportReader {
+            num_docs,
+            remaining: limit,
+            handle,
+            batch: Vec::with_capacity(num_docs),
+            pending: None,
+            found: false,
+            stopped: false,
+        }
+    }
+
+    pub fn read<R: Read>(mut self, source: R) -> Result<(), ResponseError> {
+        let mut parser = serde_json::Deserializer::from_reader(BufReader::new(source));
+        let parsed = Nested { reader: &mut self, path: DOCS_PATH }.deserialize(&mut parser);
+        match parsed {
+            Err(_) if self.stopped => Ok(()),
+            Err(cause) => Err(ResponseError::from_json(cause, EXPORT_BODY)),
+            Ok(_) if !self.found => Err(ResponseError::Shape {
+                message: "missing field `response.docs`".to_string(),
+                body: EXPORT_BODY.to_string(),
+            }),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Holds the full batch for knowing if it is the last one and sends the batch held before
+    fn flush(&mut self) -> bool {
+        let docs: Vec<&str> = self.batch.iter().map(|doc| doc.get()).collect();
+        let json = format!("[{}]", docs.join(COMMA));
+        let count = self.batch.len();
+        self.batch.clear();
+        match self.pending.replace((json, count)) {
+            None => false,
+            Some((json, count)) => (self.handle)(json, count, false),
+        }
+    }
+
+    fn finish(&mut self) {
+        if !self.batch.is_empty() && self.flush() {
+            return;
+        }
+        if let Some((json, count)) = self.pending.take() {
+            (self.handle)(json, count, true);
+        }
+    }
+}
+
+/// Shown in errors instead of the body of the response, as it is streamed and not kept
+const EXPORT_BODY: &str = "<streamed from /export>";
+
+/// Follows the keys in `path` down the json objects until the docs array
+struct Nested<'a, F> {
+    reader: &'a mut ExportReader<F>,
+    path: &'static [&'static str],
+}
+
+impl<'de, F> DeserializeSeed<'de> for Nested<'_, F>
+where
+    F: FnMut(String, usize, bool) -> bool,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        if self.path.is_empty() {
+            deserializer.deserialize_seq(self)
+        } else {
+            deserializer.deserialize_map(self)
+        }
+    }
+}
+
+impl<'de, F> Visitor<'de> for Nested<'_, F>
+where
+    F: FnMut(String, usize, bool) -> bool,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path.first() {
+            None => formatter.write_str("the array of docs"),
+            Some(key) => write!(formatter, "a object with `{}`", key),
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let Nested { reader, path } = self;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == path[0] {
+                map.next_value_seed(Nested { reader: &mut *reader, path: &path[1..] })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let reader = self.reader;
+        reader.found = true;
+        while reader.remaining > 0 {
+            let doc = match seq.next_element::<Box<RawValue>>()? {
+                None => {
+                    reader.finish();
+                    return Ok(());
+                }
+                Some(doc) => doc,
+            };
+            if doc.get().starts_with("{\"EXCEPTION\"") {
+                return Err(A::Error::custom(format!("Solr /export failed: {}", doc.get())));
+            }
+            reader.remaining -= 1;
+            reader.batch.push(doc);
+            if reader.batch.len() >= reader.num_docs && reader.flush() {
+                reader.stopped = true;
+                return Err(A::Error::custom("stopped by the receiver"));
+            }
+        }
+        // the docs after the limit are not read
+        reader.finish();
+        reader.stopped = true;
+        Err(A::Error::custom("stopped at the limit"))
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::export::*;
+
+    const EXPORT_3ROW: &str = r#"{
+  "responseHeader":{"status":0},
+  "response":{
+    "numFound":3,
+    "docs":[{
+        "id":"3007WFP",
+        "name":["Dell {\"docs\":[}} UltraSharp"]},
+      {
+        "id":"100-435805",
+        "name":["ATI Radeon X1900 ]}"]},
+      {
+        "id":"EN7800GTX",
+        "name":["ASUS Extreme N7800GTX"]}]}}"#;
+
+    const SCHEMA_FIELDS: &str = r#"{
+  "responseHeader":{"status":0,"QTime":1},
+  "fields":[{
+      "name":"id",
+      "type":"string",
+      "docValues":true,
+      "indexed":true},
+    {
+      "name":"name",
+      "type":"text_general",
+      "docValues":false},
+    {
+      "name":"*_s",
+      "type":"string",
+      "docValues":true}]}"#;
+
+    fn read_export(json: &str, num_docs: usize, limit: usize) -> BoxedResult<Vec<(String, bool)>> {
+        let mut batches = Vec::new();
+        let reader = ExportReader::new(num_docs, limit, |batch, _, last| {
+            batches.push((batch, last));
+            false
+        });
+        reader.read(json.as_bytes())?;
+        Ok(batches)
+    }
+
+    #[test]
+    fn check_export_reader_batches() {
+        let (batches, lasts): (Vec<String>, Vec<bool>) =
+            read_export(EXPORT_3ROW, 2, usize::MAX).unwrap().into_iter().unzip();
+        assert_eq!(lasts, vec![false, true]);
+        assert!(batches[0].starts_with(
+            r#"[{
+        "id":"3007WFP""#
+        ));
+        assert!(batches[0].ends_with(r#"X1900 ]}"]}]"#));
+        assert!(batches[0].contains(
+            r#"]},{
+        "id":"100-435805""#
+        ));
+        assert!(batches[1].starts_with("[{"));
+        assert!(batches[1].ends_with(r#"N7800GTX"]}]"#));
+    }
+
+    #[test]
+    fn check_export_reader_limit() {
+        let batches = read_export(EXPORT_3ROW, 4, 1).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].1);
+        assert!(!batches[0].0.contains("100-435805"));
+    }
+
+    #[test]
+    fn check_export_reader_truncated() {
+        let truncated = &EXPORT_3ROW[..EXPORT_3ROW.len() - 40];
+        let mut sent = 0;
+        let reader = ExportReader::new(2, usize::MAX, |_, _, _| {
+            sent += 1;
+            false
+        });
+        assert!(reader.read(truncated.as_bytes()).is_err());
+        assert_eq!(sent, 0);
+
+        let outside = r#"{"responseHeader":{"docs":[{"id":"1"}]},"response":{"numFound":0}}"#;
+        let missing = read_export(outside, 2, usize::MAX).unwrap_err();
+        assert!(missing.to_string().contains("response.docs"));
+
+        let failed = r#"{"response":{"docs":[{"id":"1"},{"EXCEPTION":"boom","EOF":true}]}}"#;
+        assert!(read_export(failed, 2, usize::MAX).unwrap_err().to_string().contains("boom"));
+    }
+
+    #[test]
+    fn check_export_doc_values() {
+        let fields = SchemaField::parse_schema_fields(SCHEMA_FIELDS).unwrap();
+        assert_eq!(fields.len(), 3);
+
+        let (fixed, dynamic): (Vec<SchemaField>, Vec<SchemaField>) =
+            fields.into_iter().partition(|field| !field.name.contains('*'));
+
+        assert!(SchemaField::has_doc_values("id", &fixed, &dynamic));
+        assert!(!SchemaField::has_doc_values("name", &fixed, &dynamic));
+        assert!(SchemaField::has_doc_values("color_s", &fixed, &dynamic));
+        assert!(!SchemaField::has_doc_values("missing", &fixed, &dynamic));
+    }
+}
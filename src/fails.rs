@@ -52,7 +52,7 @@ where
 }
 
 pub fn raise<T>(message: &str) -> BoxedResult<T> {
-    Err(Box::new(Failed::new(&message)))
+    Err(Box::new(Failed::new(message)))
 }
 
 // endregion
@@ -64,12 +64,12 @@ mod tests {
     #[test]
     fn check_throw_and_raise() {
         let failure = throw::<usize>("fail".to_string());
-        assert_eq!(failure.is_ok(), false);
+        assert!(failure.is_err());
 
         // TODO revamp error handling results types
         // let failerr = failure.err().unwrap();
         // assert_eq!(rethrow(failerr).is_ok(), false);
 
-        assert_eq!(raise::<usize>("fail").is_ok(), false);
+        assert!(raise::<usize>("fail").is_err());
     }
 }
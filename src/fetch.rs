@@ -1,20 +1,29 @@
-use log::debug;
-use regex::Regex;
+use glob::Pattern;
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::{error::Category, value::RawValue, Map, Value};
 
-use super::{args::Backup, connection::SolrClient, fails::*, helpers::*, steps::SolrCore};
+use std::{error::Error, fmt};
+
+use super::{
+    connection::SolrClient,
+    fails::*,
+    helpers::*,
+    steps::{Querying, SolrCore},
+};
 
 // region Solr Core
 
-impl Backup {
+impl Querying<'_> {
     pub fn inspect_core(&self) -> BoxedResult<SolrCore> {
         let diagnostics_query_url = self.get_query_for_diagnostics();
         debug!("Inspecting schema of core {} at: {}", self.options.core, diagnostics_query_url);
 
         // try sometimes for finding the greatest num_found of docs answered by the core
         // Used for fixing problems with corrupted replicas of cores with more than 1 shard
-        let times = (self.workaround_shards * 5) + 1;
+        let times = (self.source.workaround_shards * 5) + 1;
 
-        let mut res = SolrCore { num_found: 0, fields: vec![] };
+        let mut res = SolrCore { num_found: 0, fields: vec![], unique_key: None };
         for it in 0..times {
             let json = SolrClient::query_get_as_text(&diagnostics_query_url)?;
             if let Ok(next) = SolrCore::parse_core_schema(self, &json) {
@@ -24,79 +33,277 @@ impl Backup {
                 }
             }
         }
-        if res.num_found <= self.skip {
+        if res.num_found <= self.source.skip {
             throw(format!(
                 "Requested {} in --skip but found {} docs with the query.",
-                self.skip, res.num_found
+                self.source.skip, res.num_found
             ))?;
         }
+        if self.source.cursor || self.source.export {
+            res.unique_key = Some(self.inspect_unique_key()?);
+        }
+        if self.needs_field_list() {
+            res.fields = self.inspect_fields(res.fields)?;
+        }
         debug!("Core schema: {:?}", res);
         Ok(res)
     }
+
+    fn inspect_unique_key(&self) -> BoxedResult<String> {
+        let schema_url = self.options.get_core_handler_url("/schema/uniquekey?wt=json");
+        debug!("Inspecting uniqueKey of core {} at: {}", self.options.core, schema_url);
+
+        let json = SolrClient::query_get_as_text(&schema_url)?;
+        let key = SolrCore::parse_unique_key(&json)?;
+        Ok(key)
+    }
 }
 
 impl SolrCore {
-    fn parse_core_schema(gets: &Backup, json: &str) -> BoxedResult<Self> {
+    fn parse_core_schema(gets: &Querying<'_>, json: &str) -> BoxedResult<Self> {
         let core_name = &gets.options.core;
 
         let total_docs = Self::parse_num_found(json)?;
         if total_docs < 1 {
             throw(format!("Solr Core '{}'is empty!", core_name))?
         };
-        let parsed_fields = Self::parse_field_names(&json);
 
-        let core_fields = if gets.select.is_empty() {
-            match parsed_fields {
-                None => throw(format!("Missing fields to parse in Solr Core '{}'!", core_name))?,
-                Some(fields) => fields,
+        let core_fields = if gets.source.select.is_empty() {
+            match Self::parse_field_names(json) {
+                Err(cause) => throw(format!(
+                    "Missing fields to parse in Solr Core '{}': {}",
+                    core_name, cause
+                ))?,
+                Ok(fields) => fields,
             }
         } else {
-            // TODO: check if args.select fields matches parsed_fields when --validate
-            gets.select.clone()
+            // globs in args.select are matched later against the fields listed by Luke
+            gets.source.select.clone()
         };
-        let res = SolrCore { num_found: total_docs, fields: core_fields };
+        let res = SolrCore { num_found: total_docs, fields: core_fields, unique_key: None };
         Ok(res)
     }
 
-    pub fn parse_num_found(json: &str) -> BoxedResult<usize> {
-        lazy_static! {
-            static ref REGNF: Regex = Regex::new("\"numFound\":(\\d+),").unwrap();
-        }
-        match REGNF.get_group(json, 1) {
-            None => throw(format!("Error parsing numFound from solr query: {}", json))?,
-            Some(group1) => {
-                let res = group1.parse::<usize>();
-                res.or_else(|_| {
-                    throw::<usize>(format!("Error converting numFound from solr query: {}", json))
-                })
+    /// Parses the Solr json response of a query in `/select`:
+    /// ``` json
+    /// {"response":{"numFound":46,"start":0,"docs":[{"a":"b"}]},"nextCursorMark":"AoE..."}
+    /// ```
+    pub fn parse_query_response(json: &str) -> Result<QueryResponse<'_>, ResponseError> {
+        serde_json::from_str(json).map_err(|cause| ResponseError::from_json(cause, json))
+    }
+
+    pub fn parse_num_found(json: &str) -> Result<usize, ResponseError> {
+        let parsed = Self::parse_query_response(json)?;
+        Ok(parsed.response.num_found)
+    }
+
+    /// Returns the names of the fields of the first document, but the internal ones: `_*`
+    fn parse_field_names(json: &str) -> Result<Vec<String>, ResponseError> {
+        let parsed = Self::parse_query_response(json)?;
+        let row1 = parsed.response.docs.first().ok_or(ResponseError::NoDocs)?;
+
+        let doc: Map<String, Value> = serde_json::from_str(row1.get())
+            .map_err(|cause| ResponseError::from_json(cause, row1.get()))?;
+        let filtered = doc
+            .keys()
+            .filter(|s| !s.starts_with('_'))
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+        Ok(filtered)
+    }
+
+    pub fn parse_unique_key(json: &str) -> Result<String, ResponseError> {
+        let parsed: UniqueKey =
+            serde_json::from_str(json).map_err(|cause| ResponseError::from_json(cause, json))?;
+        Ok(parsed.unique_key)
+    }
+}
+
+impl QueryResponse<'_> {
+    /// Joins the docs as returned by Solr in a json array: `[{  "a": "b", "c": "d" }]`
+    pub fn docs_to_json(&self) -> String {
+        let docs: Vec<&str> = self.response.docs.iter().map(|doc| doc.get()).collect();
+        format!("[{}]", docs.join(COMMA))
+    }
+}
+
+// endregion
+
+// region Field selection
+
+impl Querying<'_> {
+    /// Whether the fields of the core are needed for `--exclude` or the globs in `--select`
+    pub(crate) fn needs_field_list(&self) -> bool {
+        !self.source.exclude.is_empty() || self.source.select.iter().any(|name| is_glob(name))
+    }
+
+    /// Lists the fields of the core with Luke and picks the ones matching `--select` and not `--exclude`.
+    /// The targets of copyField are left out because Solr fills them again when restoring.
+    fn inspect_fields(&self, first_doc: Vec<String>) -> BoxedResult<Vec<String>> {
+        let luke_url = self.options.get_core_handler_url("/admin/luke?numTerms=0&wt=json");
+        debug!("Listing fields of core {} at: {}", self.options.core, luke_url);
+        let list_fields = || -> BoxedResult<Vec<String>> {
+            Ok(SolrCore::parse_luke_fields(&SolrClient::query_get_as_text(&luke_url)?)?)
+        };
+        let listed = match list_fields() {
+            Ok(fields) => fields,
+            Err(cause) if self.source.select.is_empty() => {
+                warn!("Using the fields of the first doc because listing them failed: {}", cause);
+                first_doc
             }
+            Err(cause) => return Err(cause),
+        };
+
+        let copy_url = self.options.get_core_handler_url("/schema/copyfields?wt=json");
+        let list_targets = || -> BoxedResult<Vec<String>> {
+            Ok(SolrCore::parse_copy_targets(&SolrClient::query_get_as_text(&copy_url)?)?)
+        };
+        let copied = list_targets().unwrap_or_else(|cause| {
+            warn!("Not leaving out copyField targets because listing them failed: {}", cause);
+            Vec::new()
+        });
+        let selected = select_fields(&listed, &self.source.select, &self.source.exclude, &copied)?;
+        if selected.is_empty() {
+            return raise("Found no fields matching --select and --exclude");
         }
+        debug!("Selected fields: {:?}", selected);
+        Ok(selected)
+    }
+}
+
+impl SolrCore {
+    /// Parses the stored or docValues fields in the response of `/admin/luke`
+    fn parse_luke_fields(json: &str) -> Result<Vec<String>, ResponseError> {
+        let parsed: LukeResponse =
+            serde_json::from_str(json).map_err(|cause| ResponseError::from_json(cause, json))?;
+        let fields = parsed
+            .fields
+            .iter()
+            .filter(|(_, info)| match info.get("schema").and_then(Value::as_str) {
+                Some(flags) => flags.contains('S') || flags.contains('D'),
+                None => true,
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        Ok(fields)
+    }
+
+    /// Parses the names or globs of the `dest` of the copyField rules in `/schema/copyfields`
+    fn parse_copy_targets(json: &str) -> Result<Vec<String>, ResponseError> {
+        let parsed: CopyFields =
+            serde_json::from_str(json).map_err(|cause| ResponseError::from_json(cause, json))?;
+        Ok(parsed.copy_fields.into_iter().map(|rule| rule.dest).collect())
     }
+}
+
+fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
 
-    fn parse_field_names(json: &str) -> Option<Vec<String>> {
-        lazy_static! {
-            static ref REGFN: Regex = Regex::new("\"(\\w+)\":").unwrap();
+/// Picks the listed fields matching the globs in `select` but not the ones in `exclude`.
+/// Without `select` all but the internal `_*` fields are picked. Names in `select` are always kept.
+fn select_fields(
+    listed: &[String], select: &[String], exclude: &[String], copied: &[String],
+) -> BoxedResult<Vec<String>> {
+    let patterns = |globs: &[String]| {
+        globs.iter().map(|glob| Pattern::new(glob)).collect::<Result<Vec<_>, _>>()
+    };
+    let (selects, excludes, targets) = (patterns(select)?, patterns(exclude)?, patterns(copied)?);
+
+    let mut res: Vec<String> = select.iter().filter(|name| !is_glob(name)).cloned().collect();
+    for name in listed {
+        let wanted = if selects.is_empty() {
+            !name.starts_with('_')
+        } else {
+            selects.iter().any(|glob| glob.matches(name))
+        };
+        if wanted && !targets.iter().any(|glob| glob.matches(name)) && !res.contains(name) {
+            res.push(name.clone());
         }
-        let row1 = Self::parse_docs_from_query(json)?;
+    }
+    res.retain(|name| !excludes.iter().any(|glob| glob.matches(name)));
+    Ok(res)
+}
 
-        let matches = REGFN.get_group_values(row1, 1);
-        let filtered = matches
-            .iter()
-            .filter(|s| !s.starts_with('_'))
-            .map(|&s| s.to_string())
-            .collect::<Vec<String>>();
-        Some(filtered)
+// endregion
+
+// region Solr responses
+
+#[derive(Deserialize, Debug)]
+pub struct QueryResponse<'a> {
+    #[serde(borrow)]
+    pub response: QueryResult<'a>,
+    #[serde(rename = "nextCursorMark")]
+    pub next_cursor_mark: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct QueryResult<'a> {
+    #[serde(rename = "numFound")]
+    pub num_found: usize,
+    #[serde(borrow)]
+    pub docs: Vec<&'a RawValue>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LukeResponse {
+    fields: Map<String, Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CopyFields {
+    #[serde(rename = "copyFields")]
+    copy_fields: Vec<CopyField>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CopyField {
+    dest: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct UniqueKey {
+    #[serde(rename = "uniqueKey")]
+    unique_key: String,
+}
+
+#[derive(Debug)]
+pub enum ResponseError {
+    /// The response is not a valid json document
+    Syntax { message: String, body: String },
+    /// The response is valid json but lacks a expected key or has a key of wrong type
+    Shape { message: String, body: String },
+    /// The response has no documents for inspecting
+    NoDocs,
+}
+
+impl ResponseError {
+    pub fn from_json(cause: serde_json::Error, body: &str) -> Self {
+        let message = cause.to_string();
+        let snippet: String = body.chars().take(400).collect();
+        match cause.classify() {
+            Category::Data => ResponseError::Shape { message, body: snippet },
+            _ => ResponseError::Syntax { message, body: snippet },
+        }
     }
+}
 
-    /// Strips out: `[{  "a": "b", "c": "d" }]` from Solr json response
-    /// ``` json
-    /// {"response":{"numFound":46,"start":0,"docs":_____}}
-    /// ```
-    pub fn parse_docs_from_query(json: &str) -> Option<&str> {
-        json.find_text_between("docs\":", "}}") // -> [{  ... }]
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseError::Syntax { message, body } => {
+                write!(f, "Invalid json in Solr response: {} -> Response: {}", message, body)
+            }
+            ResponseError::Shape { message, body } => {
+                write!(f, "Unexpected Solr response: {} -> Response: {}", message, body)
+            }
+            ResponseError::NoDocs => write!(f, "Solr response has no documents"),
+        }
     }
 }
 
+impl Error for ResponseError {}
+
 // endregion
 
 #[cfg(test)]
@@ -119,32 +326,69 @@ pub mod tests {
                 {"id":"EN7800GTX/2DHTV/256M","name":["ASUS Extreme N7800GTX/2DHTV (256 MB)"],"cat":["electronics","graphics card"],"price":[479.95]}
             ]}}"#;
 
+    const CORE_CURSOR: &str = r#"{"response":{"numFound":46,"start":0,
+            "docs":[{"id":"3007WFP","name":["Dell Widescreen UltraSharp 3007WFP"]}]},
+        "nextCursorMark":"AoEnMzAwN1dGUA=="}"#;
+
+    const CORE_NESTED: &str = r#"{"response":{"start":0,
+            "docs":[{"id":"1","title":"close }} here","_version_":1,
+                "_childDocuments_":[{"id":"1.1","child_only":"x"}]}],
+            "numFound":1}}"#;
+
     #[test]
     fn check_schema_num_found() {
         let num_found = SolrCore::parse_num_found(CORE_1ROW);
         assert_eq!(num_found.ok(), Some(46));
+
+        let last_key = SolrCore::parse_num_found(CORE_NESTED);
+        assert_eq!(last_key.ok(), Some(1));
+    }
+
+    #[test]
+    fn check_select_fields() {
+        let luke = r#"{"fields":{"id":{"type":"string","schema":"I-S-------OF-----l"},
+            "attr_size":{"schema":"I-S-M------------"},"attr_tmp":{"schema":"I-S-M------------"},
+            "text":{"schema":"ITS-M------------"},"index_only":{"schema":"I---------OF-----"},
+            "_version_":{"schema":"I--D----------"}}}"#;
+        let listed = SolrCore::parse_luke_fields(luke).unwrap();
+        assert_eq!(listed, vec!["id", "attr_size", "attr_tmp", "text", "_version_"]);
+
+        let copies =
+            r#"{"copyFields":[{"source":"name","dest":"text"},{"source":"*","dest":"*_str"}]}"#;
+        let copied = SolrCore::parse_copy_targets(copies).unwrap();
+
+        let all = select_fields(&listed, &[], &[], &copied).unwrap();
+        assert_eq!(all, vec!["id", "attr_size", "attr_tmp"]);
+
+        let globs = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let picked =
+            select_fields(&listed, &globs(&["id", "attr_*"]), &globs(&["*_tmp"]), &copied).unwrap();
+        assert_eq!(picked, vec!["id", "attr_size"]);
     }
 
     #[test]
     fn check_schema_fields() {
         let fields = SolrCore::parse_field_names(CORE_1ROW);
-        assert_eq!(fields.is_some(), true);
+        assert!(fields.is_ok());
 
         let fields2 = fields.unwrap();
 
         assert_eq!(fields2.len(), 4);
-        assert_eq!(fields2.get(0).unwrap(), "id");
+        assert_eq!(fields2.first().unwrap(), "id");
         assert_eq!(fields2.get(1).unwrap(), "name");
         assert_eq!(fields2.get(2).unwrap(), "cat");
         assert_eq!(fields2.get(3).unwrap(), "price");
+
+        let nested = SolrCore::parse_field_names(CORE_NESTED).unwrap();
+        assert_eq!(nested, vec!["id", "title"]);
     }
 
     #[test]
     fn check_query_docs() {
-        let docs = SolrCore::parse_docs_from_query(CORE_3ROW);
-        assert_eq!(docs.is_some(), true);
+        let parsed = SolrCore::parse_query_response(CORE_3ROW);
+        assert!(parsed.is_ok());
 
-        let json = docs.unwrap().remove_whitespace();
+        let json = parsed.unwrap().docs_to_json().remove_whitespace();
 
         let starting = &json[..2];
         assert_eq!(starting, "[{");
@@ -155,5 +399,36 @@ pub mod tests {
 
         let rows = json.split("},{").collect::<Vec<&str>>();
         assert_eq!(rows.len(), 3);
+
+        let nested = SolrCore::parse_query_response(CORE_NESTED).unwrap();
+        assert!(nested.docs_to_json().contains(r#""title":"close }} here""#));
+    }
+
+    #[test]
+    fn check_query_cursor() {
+        let parsed = SolrCore::parse_query_response(CORE_CURSOR).unwrap();
+        assert_eq!(
+            parsed.docs_to_json(),
+            r#"[{"id":"3007WFP","name":["Dell Widescreen UltraSharp 3007WFP"]}]"#
+        );
+        assert_eq!(parsed.next_cursor_mark.as_deref(), Some("AoEnMzAwN1dGUA=="));
+
+        let no_cursor = SolrCore::parse_query_response(CORE_3ROW).unwrap();
+        assert_eq!(no_cursor.next_cursor_mark, None);
+
+        let key = SolrCore::parse_unique_key(r#"{"responseHeader":{},"uniqueKey":"id"}"#);
+        assert_eq!(key.ok().as_deref(), Some("id"));
+    }
+
+    #[test]
+    fn check_query_errors() {
+        let truncated = SolrCore::parse_query_response(&CORE_3ROW[..100]);
+        assert!(matches!(truncated, Err(ResponseError::Syntax { .. })));
+
+        let no_docs = SolrCore::parse_query_response(r#"{"response":{"numFound":1}}"#);
+        assert!(matches!(no_docs, Err(ResponseError::Shape { .. })));
+
+        let empty = SolrCore::parse_field_names(r#"{"response":{"numFound":0,"docs":[]}}"#);
+        assert!(matches!(empty, Err(ResponseError::NoDocs)));
     }
 }
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
 use regex::{Captures, Regex};
-use std::{convert::TryInto, env, path::PathBuf, str::FromStr};
+use std::{convert::TryInto, env, path::Path, str::FromStr};
 
 // region Constants
 
@@ -33,6 +33,9 @@ const ISO_SLEN: usize = 20;
 
 pub fn replace_solr_date(query: &str, pattern: &str, value: &str) -> String {
     let vlen = value.len();
+    if vlen >= ISO_SLEN || value.starts_with("NOW") || value.parse::<i64>().is_ok() {
+        return query.replace(pattern, value);
+    }
     let suffix = &ISO_DATE[vlen..];
 
     let value2 = value.append(suffix);
@@ -64,8 +67,8 @@ pub fn env_value(var_name: &str, replacement: isize) -> isize {
     }
 }
 
-pub fn get_filename(file_path: &PathBuf) -> Result<String, ()> {
-    file_path.as_path().file_name().ok_or(())?.to_os_string().into_string().or(Err(()))
+pub fn get_filename(file_path: &Path) -> Result<String, ()> {
+    file_path.file_name().ok_or(())?.to_os_string().into_string().or(Err(()))
 }
 
 // endregion
@@ -144,7 +147,7 @@ impl StringHelpers for str {
         let starts = found + prefix.len();
         let text_len = self.len();
 
-        let ulast_pos = last_pos.abs() as usize;
+        let ulast_pos = last_pos.unsigned_abs();
         let positive = last_pos > 0;
         let smaller = ulast_pos < text_len;
 
@@ -186,7 +189,7 @@ impl StringHelpers for str {
     #[inline]
     fn append_all(&self, suffixes: &[&str]) -> String {
         let mut all: Vec<&str> = Vec::with_capacity(suffixes.len() + 1);
-        all.push(&self);
+        all.push(self);
         all.extend(suffixes.iter());
         all.concat()
     }
@@ -197,7 +200,7 @@ impl StringHelpers for str {
             return self.to_string();
         }
         let mut res = prefix.to_owned();
-        res.push_str(&self);
+        res.push_str(self);
         res
     }
 
@@ -481,7 +484,7 @@ impl IntegerHelpers for i64 {
 
 pub(crate) fn print_env_vars() {
     eprintln!("Listing all env vars:");
-    for (key, val) in std::env::vars() {
+    for (key, val) in env::vars() {
         eprintln!("  {}: {}", key, val);
     }
 }
@@ -496,8 +499,18 @@ mod tests {
     fn check_starts_with_any() {
         let ok = &["true", "test"];
         let s1: &str = "test";
-        assert_eq!(s1.starts_with_any(ok), true);
+        assert!(s1.starts_with_any(ok));
         let s2: String = String::from("test");
-        assert_eq!(s2.starts_with_any(ok), true);
+        assert!(s2.starts_with_any(ok));
+    }
+
+    #[test]
+    fn check_replace_solr_date() {
+        assert_eq!(
+            replace_solr_date("d:[{begin}", "{begin}", "2020-04"),
+            "d:[2020-04-01T00:00:00Z"
+        );
+        assert_eq!(replace_solr_date("n:[{begin}", "{begin}", "10"), "n:[10");
+        assert_eq!(replace_solr_date("d:[{begin}", "{begin}", "NOW/DAY"), "d:[NOW/DAY");
     }
 }
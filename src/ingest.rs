@@ -2,22 +2,33 @@ use log::error;
 use zip::ZipArchive;
 
 use glob::{glob, PatternError};
-use std::{fmt, fs::File, io::prelude::*, path::PathBuf};
+use std::{
+    fmt,
+    fs::File,
+    io::{prelude::*, BufReader},
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
 use crate::{
-    args::{Restore, SortOrder},
+    args::{CommitMode, CommonArgs, ParallelArgs, Restore, SortOrder},
     fails::*,
     helpers::*,
+    steps::Documents,
 };
 
 type Decompressor = ZipArchive<File>;
 
+/// Extension of the entries written by `backup --format jsonl` with one doc per line
+const JSONL_EXTENSION: &str = ".jsonl";
+
 #[derive(Debug)]
 pub(crate) struct ArchiveReader {
     pub archive: Decompressor,
     pub entry_index: usize,
 }
 
+#[derive(Clone)]
 pub(crate) struct Docs {
     pub json: String,
     pub archive: String,
@@ -28,7 +39,11 @@ impl Restore {
     pub fn find_archives(&self) -> Result<Vec<PathBuf>, PatternError> {
         let wilcard = self.get_pattern();
         let listed = glob(&wilcard)?;
-        let mut found = listed.filter_map(Result::ok).collect::<Vec<_>>();
+        // skip the journals and manifests written along with the archives
+        let mut found = listed
+            .filter_map(Result::ok)
+            .filter(|path| path.extension().is_some_and(|ext| ext == "zip"))
+            .collect::<Vec<_>>();
         if self.order != SortOrder::None {
             found.sort_unstable();
         }
@@ -49,31 +64,45 @@ impl Restore {
             }
             None => format!("{}*.zip", self.options.core),
         };
-        let mut path = self.transfer.dir.clone();
+        let mut path = self.dir.clone();
         path.push(wilcard);
         let res = path.to_str().unwrap();
         res.to_string()
     }
 
+    /// Path of the journal recording the entries indexed into the core: `restore_{core}.journal`
+    pub fn get_journal_path(&self) -> PathBuf {
+        match &self.journal {
+            Some(path) => path.clone(),
+            None => self.dir.join(format!("restore_{}.journal", self.options.core)),
+        }
+    }
+
     pub fn get_update_url(&self) -> String {
+        self.options.get_update_docs_url(&self.flush, &self.transfer)
+    }
+}
+
+impl CommonArgs {
+    pub fn get_update_docs_url(&self, flush: &CommitMode, transfer: &ParallelArgs) -> String {
         // E.g: http://localhost:8983/solr/mycore/update?wt=json&overwrite=true&commitWithin=1000&useParams=my_params
         let parts: Vec<String> = vec![
-            self.options.get_core_handler_url("/update/json/docs?overwrite=true"),
-            self.flush.as_param("&"),
-            self.transfer.get_param("&"),
+            self.get_core_handler_url("/update/json/docs?overwrite=true"),
+            flush.as_param("&"),
+            transfer.get_param("&"),
         ];
         parts.concat()
     }
 }
 
 impl ArchiveReader {
-    pub(crate) fn open_archive(archive_path: &PathBuf) -> BoxedResult<Decompressor> {
+    pub(crate) fn open_archive(archive_path: &Path) -> BoxedResult<Decompressor> {
         let zipfile = File::open(archive_path)?;
         let res = ZipArchive::new(zipfile)?;
         Ok(res)
     }
 
-    pub(crate) fn create_reader(archive_path: &PathBuf) -> BoxedResult<ArchiveReader> {
+    pub(crate) fn create_reader(archive_path: &Path) -> BoxedResult<ArchiveReader> {
         let success = Self::open_archive(archive_path);
         match success {
             Err(cause) => Err(cause),
@@ -81,7 +110,68 @@ impl ArchiveReader {
         }
     }
 
-    pub(crate) fn get_archive_file_count(archive_path: &PathBuf) -> Option<usize> {
+    /// Reads the entries sending the docs of the `jsonl` ones in batches of `batch_docs` lines.
+    /// The batches are named as `{entry}#{begin}..{end}` with the offsets of their lines, so they
+    /// don't depend on `batch_docs`. The lines where `skip_line` is true are left out, ending the batch.
+    /// The `handle` returns true for stopping.
+    pub(crate) fn read_batches<S, F>(
+        mut self, batch_docs: usize, skip_line: S, mut handle: F,
+    ) -> BoxedResult<()>
+    where
+        S: Fn(&str, usize) -> bool,
+        F: FnMut(String, String) -> bool,
+    {
+        for index in 0..self.archive.len() {
+            let mut compressed = self.archive.by_index(index)?;
+            let entry_name = compressed.name().to_string();
+            if !is_json_lines(&entry_name) {
+                let mut contents = String::new();
+                compressed.read_to_string(&mut contents)?;
+                if handle(entry_name, contents) {
+                    return Ok(());
+                }
+                continue;
+            }
+            let mut batch = Vec::with_capacity(batch_docs);
+            let mut begin = 0;
+            let mut end = 0;
+            for (num, line) in BufReader::new(compressed).lines().enumerate() {
+                let line = line?;
+                end = num + 1;
+                let skipped = skip_line(&entry_name, num);
+                if !skipped && !line.trim().is_empty() {
+                    batch.push(line);
+                }
+                if skipped || batch.len() >= batch_docs {
+                    let last = if skipped { num } else { end };
+                    if send_batch(&mut handle, &entry_name, &mut batch, begin..last) {
+                        return Ok(());
+                    }
+                    begin = end;
+                }
+            }
+            if send_batch(&mut handle, &entry_name, &mut batch, begin..end) {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the name and the docs of the entry at `index`. The `jsonl` docs are joined in a json array.
+    pub(crate) fn read_entry(&mut self, index: usize) -> BoxedResult<(String, String)> {
+        let mut compressed = self.archive.by_index(index)?;
+        let zip_name = compressed.name().to_string();
+        let mut zip_contents = String::new();
+        if let Err(cause) = compressed.read_to_string(&mut zip_contents) {
+            return throw(format!("{}: {}", zip_name, cause));
+        }
+        if is_json_lines(&zip_name) {
+            zip_contents = join_json_lines(zip_contents.lines());
+        }
+        Ok((zip_name, zip_contents))
+    }
+
+    pub(crate) fn get_archive_file_count(archive_path: &Path) -> Option<usize> {
         let success = Self::open_archive(archive_path);
         match success {
             Err(_) => None,
@@ -101,18 +191,14 @@ impl Iterator for ArchiveReader {
         if self.entry_index >= file_count {
             return None;
         }
-        let mut compressed = self.archive.by_index(self.entry_index).unwrap();
-        let zip_name = compressed.name().to_string();
-        let mut zip_contents = String::new();
-        let reading = compressed.read_to_string(&mut zip_contents);
-        match reading {
+        match self.read_entry(self.entry_index) {
             Err(cause) => {
-                error!("error reading archive #{} {}: {}", self.entry_index + 1, zip_name, cause);
+                error!("error reading archive #{}: {}", self.entry_index + 1, cause);
                 None
             }
-            Ok(_) => {
+            Ok(entry) => {
                 self.entry_index += 1;
-                Some((zip_name, zip_contents))
+                Some(entry)
             }
         }
     }
@@ -128,12 +214,64 @@ impl Iterator for ArchiveReader {
     }
 }
 
+pub(crate) fn is_json_lines(entry_name: &str) -> bool {
+    entry_name.ends_with(JSONL_EXTENSION)
+}
+
+/// Sends the docs of the lines in `range` of the entry, when there are some, as a json array
+fn send_batch<F>(
+    handle: &mut F, entry_name: &str, batch: &mut Vec<String>, range: Range<usize>,
+) -> bool
+where
+    F: FnMut(String, String) -> bool,
+{
+    if batch.is_empty() {
+        return false;
+    }
+    let name = format!("{}#{}..{}", entry_name, range.start, range.end);
+    let docs = join_json_lines(batch.iter());
+    batch.clear();
+    handle(name, docs)
+}
+
+/// Splits the name of a batch of a `jsonl` entry in the entry name and the offsets of its lines
+pub(crate) fn parse_batch_name(name: &str) -> Option<(&str, Range<usize>)> {
+    let (entry, lines) = name.split_once('#')?;
+    let (begin, end) = lines.split_once("..")?;
+    Some((entry, begin.parse().ok()?..end.parse().ok()?))
+}
+
+/// Joins the docs of a `jsonl` entry in a json array for posting them to solr
+fn join_json_lines<S: AsRef<str>>(lines: impl Iterator<Item = S>) -> String {
+    let mut json = String::from("[");
+    for line in lines {
+        let doc = line.as_ref().trim();
+        if doc.is_empty() {
+            continue;
+        }
+        if json.len() > 1 {
+            json.push(',');
+        }
+        json.push_str(doc);
+    }
+    json.push(']');
+    json
+}
+
 impl Docs {
     pub fn new(archive_name: String, entry_name: String, documents: String) -> Self {
         Docs { archive: archive_name, entry: entry_name, json: documents }
     }
 }
 
+impl From<Documents> for Docs {
+    /// Used when copying the docs retrieved from a core straight into another
+    fn from(retrieved: Documents) -> Self {
+        let entry = retrieved.step.get_docs_filename();
+        Docs { archive: retrieved.step.url, entry, json: retrieved.docs }
+    }
+}
+
 impl fmt::Display for Docs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "archive: {} file: {}", self.archive, self.entry)
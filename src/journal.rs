@@ -0,0 +1,285 @@
+use log::debug;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{prelude::*, BufReader};
+use std::{ops::Range, path::Path, sync::Mutex};
+
+use crate::{
+    fails::*,
+    ingest::parse_batch_name,
+    steps::{Paging, Step},
+};
+
+// region Journal
+
+/// Records the steps of a backup whose documents were stored in a finished zip archive
+#[derive(Debug)]
+pub struct Journal {
+    writer: Mutex<File>,
+    done: HashSet<(String, usize)>,
+    last: HashMap<String, usize>,
+    archives: HashSet<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub archive: String,
+    /// The `key` of the step identifying it across executions
+    pub step: String,
+    pub curr: usize,
+    /// Whether this is the last page of a chain of cursorMark or /export pages
+    pub last: bool,
+}
+
+impl Journal {
+    /// Creates a new journal or, when resuming, loads the steps already recorded in it
+    pub fn open(journal_path: &Path, resume: bool) -> BoxedResult<Self> {
+        let mut done = HashSet::new();
+        let mut last = HashMap::new();
+        let mut archives = HashSet::new();
+        if resume {
+            for entry in load_entries::<JournalEntry>(journal_path)? {
+                if entry.last {
+                    last.insert(entry.step.clone(), entry.curr);
+                }
+                done.insert((entry.step, entry.curr));
+                archives.insert(entry.archive);
+            }
+            debug!("Loaded {} steps from journal {:?}", done.len(), journal_path);
+        }
+        let file = open_for_writing(journal_path, resume)?;
+        Ok(Journal { writer: Mutex::new(file), done, last, archives })
+    }
+
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Checks if the docs of the step were already stored in a previous execution.
+    /// Chained pages are only complete when all pages until the last one were stored.
+    pub fn is_done(&self, step: &Step) -> bool {
+        match &step.paging {
+            Paging::Offset => self.is_page_done(step),
+            Paging::Cursor { num_docs, .. } | Paging::Export { num_docs, .. } => {
+                match self.last.get(&step.key) {
+                    None => false,
+                    Some(&last_page) => (step.curr..=last_page)
+                        .step_by(*num_docs)
+                        .all(|curr| self.done.contains(&(step.key.clone(), curr))),
+                }
+            }
+        }
+    }
+
+    /// Checks if this page of docs was stored in a previous execution, even when its chain was not finished
+    pub fn is_page_done(&self, step: &Step) -> bool {
+        self.done.contains(&(step.key.clone(), step.curr))
+    }
+
+    /// Checks if the archive was finished and recorded in a previous execution
+    pub fn has_archive(&self, archive: &str) -> bool {
+        self.archives.contains(archive)
+    }
+
+    /// Appends the steps stored in a finished zip archive to the journal
+    pub fn record(&self, entries: &[JournalEntry]) -> std::io::Result<()> {
+        append_entries(&self.writer, entries)
+    }
+}
+
+// endregion
+
+// region Restore journal
+
+/// Records the entries of zip archives whose documents were accepted by Solr in a restore
+#[derive(Debug)]
+pub struct RestoreJournal {
+    writer: Mutex<File>,
+    done: HashSet<(String, String)>,
+    lines: HashMap<(String, String), Vec<Range<usize>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RestoredEntry {
+    pub archive: String,
+    pub entry: String,
+}
+
+impl RestoreJournal {
+    /// Creates a new journal or, when resuming, loads the entries already indexed.
+    /// The journal of a previous restore is only started over when `discard` is given.
+    pub fn open(journal_path: &Path, resume: bool, discard: bool) -> BoxedResult<Self> {
+        if !resume && !discard && journal_path.exists() {
+            throw(format!(
+                "Found the journal of a previous restore: {:?}\n note: use --resume for \
+                 continuing it or --discard-journal for starting over",
+                journal_path
+            ))?;
+        }
+        let mut done = HashSet::new();
+        let mut lines: HashMap<_, Vec<_>> = HashMap::new();
+        if resume {
+            for entry in load_entries::<RestoredEntry>(journal_path)? {
+                if let Some((name, range)) = parse_batch_name(&entry.entry) {
+                    lines.entry((entry.archive.clone(), name.to_string())).or_default().push(range);
+                }
+                done.insert((entry.archive, entry.entry));
+            }
+            debug!("Loaded {} entries from journal {:?}", done.len(), journal_path);
+        }
+        let file = open_for_writing(journal_path, resume)?;
+        Ok(RestoreJournal { writer: Mutex::new(file), done, lines })
+    }
+
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn is_done(&self, archive: &str, entry: &str) -> bool {
+        self.done.contains(&(archive.to_string(), entry.to_string()))
+    }
+
+    /// Checks if the line of a `jsonl` entry was sent in a batch of a previous execution,
+    /// even when it was restored with other `--batch-docs`
+    pub fn is_line_done(&self, archive: &str, entry: &str, line: usize) -> bool {
+        match self.lines.get(&(archive.to_string(), entry.to_string())) {
+            None => false,
+            Some(ranges) => ranges.iter().any(|range| range.contains(&line)),
+        }
+    }
+
+    /// Counts the entries of the archive already indexed in a previous execution.
+    /// The batches of `jsonl` entries, named as `{entry}#{begin}..{end}`, are not counted.
+    pub fn count_done(&self, archive: &str) -> usize {
+        self.done.iter().filter(|(name, entry)| name == archive && !entry.contains('#')).count()
+    }
+
+    /// Counts the entries and the batches of `jsonl` entries of the archive already indexed
+    pub fn count_sent(&self, archive: &str) -> usize {
+        self.done.iter().filter(|(name, _)| name == archive).count()
+    }
+
+    /// Appends a entry accepted by Solr to the journal
+    pub fn record(&self, archive: &str, entry: &str) -> std::io::Result<()> {
+        let restored = RestoredEntry { archive: archive.to_string(), entry: entry.to_string() };
+        append_entries(&self.writer, &[restored])
+    }
+}
+
+// endregion
+
+// region Journal files
+
+fn load_entries<T: DeserializeOwned>(journal_path: &Path) -> BoxedResult<Vec<T>> {
+    let existing = File::open(journal_path).or_else(|cause| {
+        throw(format!("Cannot resume from journal {:?}: {}", journal_path, cause))
+    })?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(existing).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+    Ok(entries)
+}
+
+/// Appends to the journal when resuming, otherwise starts it over
+fn open_for_writing(journal_path: &Path, resume: bool) -> std::io::Result<File> {
+    OpenOptions::new().create(true).write(true).append(resume).truncate(!resume).open(journal_path)
+}
+
+fn append_entries<T: Serialize>(writer: &Mutex<File>, entries: &[T]) -> std::io::Result<()> {
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+    let mut file = writer.lock().unwrap();
+    file.write_all(lines.as_bytes())?;
+    file.flush()?;
+    Ok(())
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::journal::*;
+
+    fn entry(key: &str, curr: usize, last: bool) -> JournalEntry {
+        JournalEntry { archive: "a.zip".to_string(), step: key.to_string(), curr, last }
+    }
+
+    fn step(key: &str, curr: usize, paging: Paging) -> Step {
+        Step { curr, url: format!("q&key={}", key), paging, key: key.to_string() }
+    }
+
+    #[test]
+    fn check_journal_resume() {
+        let path = std::env::temp_dir().join("solrcopy_check_journal_resume.journal");
+
+        let journal = Journal::open(&path, false).unwrap();
+        journal.record(&[entry("0", 0, true), entry("10", 10, true)]).unwrap();
+        journal.record(&[entry("a..b@0", 0, false), entry("a..b@0", 10, true)]).unwrap();
+        journal.record(&[entry("b..c@0", 10, true)]).unwrap();
+        drop(journal);
+
+        let resumed = Journal::open(&path, true).unwrap();
+        assert_eq!(resumed.len(), 5);
+
+        assert!(resumed.is_done(&step("0", 0, Paging::Offset)));
+        assert!(!resumed.is_done(&step("20", 20, Paging::Offset)));
+        assert!(resumed.has_archive("a.zip"));
+        assert!(!resumed.has_archive("b.zip"));
+
+        let cursor = Paging::Cursor { mark: "*".to_string(), limit: 100, num_docs: 10 };
+        assert!(resumed.is_done(&step("a..b@0", 0, cursor.clone())));
+        assert!(!resumed.is_done(&step("b..c@0", 0, cursor.clone())));
+        assert!(resumed.is_page_done(&step("b..c@0", 10, cursor)));
+
+        let fresh = Journal::open(&path, false).unwrap();
+        assert_eq!(fresh.len(), 0);
+        drop(fresh);
+        assert_eq!(Journal::open(&path, true).unwrap().len(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn check_restore_journal_resume() {
+        let path = std::env::temp_dir().join("solrcopy_check_restore_journal.journal");
+
+        let journal = RestoreJournal::open(&path, false, true).unwrap();
+        journal.record("a.zip", "docs_at_000000001.json").unwrap();
+        journal.record("a.zip", "docs_at_000000011.json").unwrap();
+        journal.record("b.zip", "docs_at_000000021.json").unwrap();
+        drop(journal);
+
+        let resumed = RestoreJournal::open(&path, true, false).unwrap();
+        assert_eq!(resumed.len(), 3);
+        assert_eq!(resumed.count_done("a.zip"), 2);
+        assert!(resumed.is_done("b.zip", "docs_at_000000021.json"));
+        assert!(!resumed.is_done("b.zip", "docs_at_000000001.json"));
+        drop(resumed);
+        assert!(RestoreJournal::open(&path, false, false).is_err());
+
+        let journal = RestoreJournal::open(&path, true, false).unwrap();
+        journal.record("c.zip", "docs_at_000000001.jsonl#0..4").unwrap();
+        journal.record("c.zip", "docs_at_000000001.jsonl#5..8").unwrap();
+        drop(journal);
+
+        let resumed = RestoreJournal::open(&path, true, false).unwrap();
+        assert_eq!(resumed.count_done("c.zip"), 0);
+        assert_eq!(resumed.count_sent("c.zip"), 2);
+        assert!(resumed.is_line_done("c.zip", "docs_at_000000001.jsonl", 3));
+        assert!(!resumed.is_line_done("c.zip", "docs_at_000000001.jsonl", 4));
+        assert!(resumed.is_line_done("c.zip", "docs_at_000000001.jsonl", 7));
+        assert!(!resumed.is_line_done("c.zip", "docs_at_000000001.jsonl", 8));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -23,17 +23,28 @@ extern crate lazy_static;
 mod args;
 mod backup;
 mod bars;
+mod cloud;
 mod commit;
 mod connection;
+mod copy;
+mod create;
 mod delete;
+mod dryrun;
+mod export;
 mod fails;
 mod fetch;
 mod helpers;
 mod ingest;
+mod journal;
+mod manifest;
 mod restore;
 mod save;
+mod schema;
+mod slicing;
 mod state;
 mod steps;
+mod transform;
+mod verify;
 
 use simplelog::{
     CombinedLogger, Config, LevelFilter, SharedLogger, TermLogger, TerminalMode, WriteLogger,
@@ -41,6 +52,7 @@ use simplelog::{
 use structopt::StructOpt;
 
 use crate::args::Arguments;
+use crate::connection::Settings;
 use crate::fails::{throw, BoxedResult};
 
 use std::fs::File;
@@ -49,10 +61,15 @@ use std::str::FromStr;
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let parsed = Arguments::parse_from_args()?;
     parsed.start_log()?;
+    if let Some(options) = parsed.get_options() {
+        Settings::from_args(options)?.install();
+    }
 
     match parsed {
         Arguments::Backup(get) => backup::backup_main(get),
         Arguments::Restore(put) => restore::restore_main(put),
+        Arguments::Copy(cpy) => copy::copy_main(cpy),
+        Arguments::Verify(ver) => verify::verify_main(ver),
         Arguments::Commit(cmd) => commit::commit_main(cmd),
         Arguments::Delete(del) => delete::delete_main(del),
     }
@@ -70,7 +87,7 @@ impl Arguments {
     }
 
     fn start_log(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let options = self.get_options();
+        let options = self.get_logging();
 
         let mut enabled: Vec<Box<dyn SharedLogger>> = Vec::new();
         if !options.is_quiet() {
@@ -1,21 +1,40 @@
 use crossbeam_channel::{bounded, Receiver, Sender};
 use crossbeam_utils::thread;
 use log::{debug, error, info, trace};
+use serde_json::value::RawValue;
 
 use std::sync::{
     atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc,
 };
-use std::{path::PathBuf, time::Instant};
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
 use crate::{
-    args::Restore, bars::*, connection::SolrClient, fails::*, helpers::*, ingest::*, state::*,
+    args::{CommonArgs, ParallelArgs, Restore},
+    bars::*,
+    cloud::ShardRouter,
+    connection::SolrClient,
+    create::create_target_main,
+    dryrun::dry_run_main,
+    fails::*,
+    helpers::*,
+    ingest::*,
+    journal::RestoreJournal,
+    manifest::Manifest,
+    save::DeadLetters,
+    schema::apply_schema_main,
+    state::*,
+    transform::Transformer,
 };
 
 pub(crate) fn restore_main(params: Restore) -> BoxedError {
     debug!("# RESTORE {:?}", params);
 
-    let found = params.find_archives()?;
+    let mut found = params.find_archives()?;
 
     if found.is_empty() {
         throw(format!(
@@ -25,11 +44,34 @@ pub(crate) fn restore_main(params: Restore) -> BoxedError {
         ))?;
     }
 
+    let transformer = Transformer::from_args(&params.transform)?;
+    if params.dry_run {
+        return dry_run_main(&params, &found, transformer.as_ref());
+    }
+    create_target_main(&params)?;
+    if params.apply_schema {
+        apply_schema_main(&params)?;
+    }
+    let journal =
+        RestoreJournal::open(&params.get_journal_path(), params.resume, params.discard_journal)?;
+    let dead_letters = params
+        .dead_letter
+        .as_ref()
+        .map(|dir| DeadLetters::write_on(dir, &params.options.core, DEAD_LETTER_FILES));
+    if params.resume {
+        found.retain(|archive| !is_fully_restored(archive, &journal));
+        info!("Resuming restore skipping {} entries already indexed.", journal.len());
+        if found.is_empty() {
+            info!("All archives were already restored into core {}.", params.options.core);
+            return Ok(());
+        }
+    }
+
     let core = params.options.core.clone();
     info!(
         "Found {} zip archives in {:?} for updating into core {:?}",
         found.len(),
-        params.transfer.dir,
+        params.dir,
         core
     );
 
@@ -38,15 +80,29 @@ pub(crate) fn restore_main(params: Restore) -> BoxedError {
         &format!("Waiting before processing {}...", core),
     );
 
-    pre_post_processing(&params, false)?;
+    pre_post_processing(&params.options, params.disable_replication, false)?;
 
     let started = Instant::now();
 
-    let updated = unzip_archives_and_send(&params, &found)?;
+    let update_url = params.get_update_url();
+    let router =
+        if params.by_shard { ShardRouter::discover(&params.options, &update_url)? } else { None };
+    if let Some(routing) = &router {
+        info!("Routing docs to the leaders of shards: {}", routing.get_shard_names().join(", "));
+    }
+
+    let indexing = Indexing::new(update_url, &params.transfer)
+        .with_router(router)
+        .with_journal(journal)
+        .with_transformer(transformer)
+        .with_dead_letters(dead_letters)
+        .with_bisect(params.bisect);
+
+    let updated = unzip_archives_and_send(&params, &found, indexing)?;
 
     info!("Updated {} batches in solr core {} in {:?}.", updated, core, started.elapsed());
 
-    pre_post_processing(&params, true)?;
+    pre_post_processing(&params.options, params.disable_replication, true)?;
 
     if updated > 0 {
         wait_with_progress(params.transfer.delay_after, "Waiting after all processing...");
@@ -56,17 +112,91 @@ pub(crate) fn restore_main(params: Restore) -> BoxedError {
 
 // region Processing
 
-fn unzip_archives_and_send(params: &Restore, found: &[PathBuf]) -> BoxedResult<usize> {
-    let doc_count = estimate_batch_count(found)?;
+/// Max number of rejected batches stored in each dead letter zip file
+pub(crate) const DEAD_LETTER_FILES: usize = 40;
+
+/// Shared state of the writer threads updating the core
+pub(crate) struct Indexing {
+    url: String,
+    max_errors: usize,
+    delay: usize,
+    error_count: AtomicUsize,
+    journal: Option<RestoreJournal>,
+    transformer: Option<Transformer>,
+    dead_letters: Option<DeadLetters>,
+    bisect: bool,
+    router: Option<ShardRouter>,
+}
+
+impl Indexing {
+    pub(crate) fn new(url: String, transfer: &ParallelArgs) -> Self {
+        Indexing {
+            url,
+            max_errors: transfer.max_errors,
+            delay: transfer.delay_per_request,
+            error_count: AtomicUsize::new(0),
+            journal: None,
+            transformer: None,
+            dead_letters: None,
+            bisect: false,
+            router: None,
+        }
+    }
+
+    /// Records the entries indexed and skips the ones already recorded
+    pub(crate) fn with_journal(mut self, journal: RestoreJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    pub(crate) fn with_transformer(mut self, transformer: Option<Transformer>) -> Self {
+        self.transformer = transformer;
+        self
+    }
+
+    pub(crate) fn with_dead_letters(mut self, dead_letters: Option<DeadLetters>) -> Self {
+        self.dead_letters = dead_letters;
+        self
+    }
+
+    /// Splits the batches rejected by Solr for indexing all docs except the bad ones
+    pub(crate) fn with_bisect(mut self, bisect: bool) -> Self {
+        self.bisect = bisect;
+        self
+    }
+
+    /// Sends the docs of each shard straight to the shard leader
+    pub(crate) fn with_router(mut self, router: Option<ShardRouter>) -> Self {
+        self.router = router;
+        self
+    }
+
+    fn transform<'a>(&self, json: &'a str) -> Result<Cow<'a, str>, serde_json::Error> {
+        match &self.transformer {
+            None => Ok(Cow::Borrowed(json)),
+            Some(transformer) => transformer.apply(json).map(Cow::Owned),
+        }
+    }
+}
+
+fn unzip_archives_and_send(
+    params: &Restore, found: &[PathBuf], indexing: Indexing,
+) -> BoxedResult<usize> {
+    let journal = indexing.journal.as_ref().unwrap();
+    let doc_count = estimate_batch_count(params, found, journal)?;
     let mut updated = 0;
 
     let core = params.options.core.clone();
     info!("Estimated {} batches for indexing in solr core {}", doc_count, core);
 
+    debug!("Solr Update Handler: {}", indexing.url);
+
     thread::scope(|pool| {
+        let indexing = &indexing;
         let transfer = &params.transfer;
         let readers_channel = transfer.readers * 2;
         let writers_channel = transfer.writers * 2;
+        let batch_docs = params.batch_docs;
 
         let (generator, sequence) = bounded::<&PathBuf>(readers_channel);
         let (sender, receiver) = bounded::<Docs>(writers_channel);
@@ -86,7 +216,8 @@ fn unzip_archives_and_send(params: &Restore, found: &[PathBuf]) -> BoxedResult<u
             pool.builder()
                 .name(thread_name)
                 .spawn(move |_| {
-                    start_reading_archive(reader, iterator, producer);
+                    let journal = indexing.journal.as_ref().unwrap();
+                    start_reading_archive(reader, iterator, producer, journal, batch_docs);
                     debug!("Finished reader #{}", reader);
                 })
                 .unwrap();
@@ -94,25 +225,16 @@ fn unzip_archives_and_send(params: &Restore, found: &[PathBuf]) -> BoxedResult<u
         drop(sequence);
         drop(sender);
 
-        let update_errors = Arc::new(AtomicUsize::new(0));
-        let update_hadler_url = params.get_update_url();
-        debug!("Solr Update Handler: {}", update_hadler_url);
-
         for iw in 0..transfer.writers {
             let consumer = receiver.clone();
             let updater = progress.clone();
 
-            let url = update_hadler_url.clone();
-            let arcerr = Arc::clone(&update_errors);
-            let merr = params.transfer.max_errors;
-            let delay = params.transfer.delay_per_request;
-
             let writer = iw;
             let thread_name = format!("Writer_{}", writer);
             pool.builder()
                 .name(thread_name)
                 .spawn(move |_| {
-                    start_indexing_docs(writer, &url, consumer, updater, &arcerr, merr, delay);
+                    start_indexing_docs(writer, consumer, updater, indexing);
                     debug!("Finished writer #{}", writer);
                 })
                 .unwrap();
@@ -141,25 +263,51 @@ fn finish_sending(params: &Restore, updated: usize) -> BoxedResult<usize> {
     }
 }
 
-fn estimate_batch_count(found: &[PathBuf]) -> BoxedResult<usize> {
-    // Estimate number of json files inside all zip files
-    let zip_count = found.len();
+fn estimate_batch_count(
+    params: &Restore, found: &[PathBuf], journal: &RestoreJournal,
+) -> BoxedResult<usize> {
+    // Count the json files and the batches of jsonl lines with the manifests of the backups
+    // and estimate the archives without one by the number of files inside the first one
+    let summaries = Manifest::load_summaries(&params.dir);
 
     let first = found.first().unwrap();
-    let file_count = ArchiveReader::get_archive_file_count(first);
-    match file_count {
+    let file_count = match ArchiveReader::get_archive_file_count(first) {
         None => throw(format!("Error opening archive: {:?}", first))?,
-        Some(doc_count) => {
-            let doc_total = doc_count * zip_count;
-            Ok(doc_total)
-        }
+        Some(count) => count,
+    };
+    let batch_total: usize = found
+        .iter()
+        .map(|archive| match summaries.get(&get_filename(archive).unwrap_or_default()) {
+            Some(summary) => summary.count_batches(params.batch_docs),
+            None => file_count,
+        })
+        .sum();
+    let done: usize = found
+        .iter()
+        .filter_map(|archive| get_filename(archive).ok())
+        .map(|name| journal.count_sent(&name))
+        .sum();
+    Ok(batch_total.saturating_sub(done))
+}
+
+fn is_fully_restored(archive_path: &Path, journal: &RestoreJournal) -> bool {
+    let zip_name = match get_filename(archive_path) {
+        Ok(name) => name,
+        Err(_) => return false,
+    };
+    match ArchiveReader::get_archive_file_count(archive_path) {
+        None => false,
+        Some(entry_count) => journal.count_done(&zip_name) >= entry_count,
     }
 }
 
-fn pre_post_processing(params: &Restore, enable: bool) -> BoxedResult<()> {
-    let core = params.options.core.as_str();
+/// Disables the replication of the core before updating it and enables it again afterwards
+pub(crate) fn pre_post_processing(
+    target: &CommonArgs, disable_replication: bool, enable: bool,
+) -> BoxedResult<()> {
+    let core = target.core.as_str();
 
-    if params.disable_replication {
+    if disable_replication {
         let (verb, handler_path) = if enable {
             ("enabling", "replication?command=enablereplication")
         } else {
@@ -167,7 +315,7 @@ fn pre_post_processing(params: &Restore, enable: bool) -> BoxedResult<()> {
         };
         info!("Now {} replication in {}.", verb, core);
 
-        let url = params.options.get_core_handler_url(handler_path);
+        let url = target.get_core_handler_url(handler_path);
         SolrClient::query_get_as_text(&url)?;
     }
     Ok(())
@@ -180,7 +328,7 @@ fn pre_post_processing(params: &Restore, enable: bool) -> BoxedResult<()> {
 fn start_listing_archives<'a>(found: &'a [PathBuf], generator: Sender<&'a PathBuf>) {
     let archives = found.iter();
     for archive in archives {
-        let status = generator.send(&archive);
+        let status = generator.send(archive);
         if status.is_err() {
             break;
         }
@@ -188,7 +336,10 @@ fn start_listing_archives<'a>(found: &'a [PathBuf], generator: Sender<&'a PathBu
     drop(generator);
 }
 
-fn start_reading_archive(reader: usize, iterator: Receiver<&PathBuf>, producer: Sender<Docs>) {
+fn start_reading_archive(
+    reader: usize, iterator: Receiver<&PathBuf>, producer: Sender<Docs>, journal: &RestoreJournal,
+    batch_docs: usize,
+) {
     let ctrl_c = monitor_term_sinal();
 
     loop {
@@ -197,7 +348,8 @@ fn start_reading_archive(reader: usize, iterator: Receiver<&PathBuf>, producer:
             break;
         }
         let archive_path = received.unwrap();
-        let failed = handle_reading_archive(reader, &producer, archive_path, &ctrl_c);
+        let failed =
+            handle_reading_archive(reader, &producer, archive_path, journal, batch_docs, &ctrl_c);
         if failed || ctrl_c.aborted() {
             break;
         }
@@ -206,24 +358,29 @@ fn start_reading_archive(reader: usize, iterator: Receiver<&PathBuf>, producer:
 }
 
 fn handle_reading_archive(
-    reader: usize, producer: &Sender<Docs>, archive_path: &PathBuf, ctrl_c: &Arc<AtomicBool>,
+    reader: usize, producer: &Sender<Docs>, archive_path: &Path, journal: &RestoreJournal,
+    batch_docs: usize, ctrl_c: &Arc<AtomicBool>,
 ) -> bool {
     let zip_name: String = get_filename(archive_path).unwrap();
     trace!("Reading zip archive: {}", zip_name);
-    let can_open = ArchiveReader::create_reader(&archive_path);
-    match can_open {
-        Ok(archive_reader) => {
-            for (entry_name, entry_contents) in archive_reader {
-                trace!("  Uncompressing json: '{}' from '{}'", entry_name, zip_name);
-
-                let docs = Docs::new(zip_name.clone(), entry_name, entry_contents);
-                let status = producer.send(docs);
-                if status.is_err() || ctrl_c.aborted() {
-                    return true;
-                }
+    let mut stopped = false;
+    let reading = ArchiveReader::create_reader(archive_path).and_then(|archive_reader| {
+        let skip_line = |entry: &str, line| journal.is_line_done(&zip_name, entry, line);
+        archive_reader.read_batches(batch_docs, skip_line, |entry_name, entry_contents| {
+            if journal.is_done(&zip_name, &entry_name) {
+                trace!("  Skipping json already indexed: '{}' from '{}'", entry_name, zip_name);
+                return false;
             }
-            false
-        }
+            trace!("  Uncompressing json: '{}' from '{}'", entry_name, zip_name);
+
+            let docs = Docs::new(zip_name.clone(), entry_name, entry_contents);
+            let status = producer.send(docs);
+            stopped = status.is_err() || ctrl_c.aborted();
+            stopped
+        })
+    });
+    match reading {
+        Ok(_) => stopped,
         Err(cause) => {
             error!("Error in thread #{} while reading docs in zip: {}", reader, cause);
             true
@@ -231,9 +388,8 @@ fn handle_reading_archive(
     }
 }
 
-fn start_indexing_docs(
-    writer: usize, url: &str, consumer: Receiver<Docs>, progress: Sender<u64>,
-    error_count: &Arc<AtomicUsize>, max_errors: usize, delay: usize,
+pub(crate) fn start_indexing_docs(
+    writer: usize, consumer: Receiver<Docs>, progress: Sender<u64>, indexing: &Indexing,
 ) {
     let ctrl_c = monitor_term_sinal();
 
@@ -244,56 +400,145 @@ fn start_indexing_docs(
             break;
         }
         let docs = received.unwrap();
-        let failed =
-            send_to_solr(docs, writer, url, &mut client, &progress, error_count, max_errors);
+        let failed = send_to_solr(docs, writer, &mut client, &progress, indexing);
         if failed || ctrl_c.aborted() {
             break;
-        } else if delay > 0 {
-            wait_by(delay);
+        } else if indexing.delay > 0 {
+            wait_by(indexing.delay);
         }
     }
     drop(consumer);
 }
 
 fn send_to_solr(
-    docs: Docs, writer: usize, url: &str, client: &mut SolrClient, progress: &Sender<u64>,
-    error_count: &Arc<AtomicUsize>, max_errors: usize,
+    docs: Docs, writer: usize, client: &mut SolrClient, progress: &Sender<u64>, indexing: &Indexing,
 ) -> bool {
-    let failed = client.post_as_json(&url, docs.json.as_str());
-    if let Err(cause) = failed {
-        let current = error_count.fetch_add(1, Ordering::SeqCst);
-        error!(
-            "Error #{}/{} in thread #{} when indexing solr core:\n{}{:?}",
-            current, max_errors, writer, cause, docs
-        );
-        current > max_errors
-    } else {
-        let status = progress.send(0);
-        status.is_err()
+    let posted = match &indexing.router {
+        None => post_or_bisect(&docs, &indexing.url, writer, client, indexing),
+        Some(router) => post_by_shard(&docs, router, writer, client, indexing),
+    };
+    match posted {
+        Err(exceeded) => exceeded,
+        Ok(_) => {
+            if let Some(journal) = &indexing.journal {
+                if let Err(cause) = journal.record(&docs.archive, &docs.entry) {
+                    error!("Error in thread #{} writing restore journal: {}", writer, cause);
+                }
+            }
+            let status = progress.send(0);
+            status.is_err()
+        }
+    }
+}
+
+/// Posts the docs of each shard to the update url of the shard leader
+fn post_by_shard(
+    docs: &Docs, router: &ShardRouter, writer: usize, client: &mut SolrClient, indexing: &Indexing,
+) -> Result<(), bool> {
+    let mut outcome = Ok(());
+    for (url, group) in router.group(docs, indexing.transformer.as_ref()) {
+        match post_or_bisect(&group, url, writer, client, indexing) {
+            Err(true) => return Err(true),
+            posted => outcome = outcome.and(posted),
+        }
     }
+    outcome
+}
+
+/// Posts the docs splitting them in halves while Solr rejects them when bisecting.
+/// Fails when some docs were not indexed telling if `max_errors` was exceeded.
+fn post_or_bisect(
+    docs: &Docs, url: &str, writer: usize, client: &mut SolrClient, indexing: &Indexing,
+) -> Result<(), bool> {
+    let posted = match indexing.transform(&docs.json) {
+        Ok(json) => client.post_as_json(url, &json),
+        Err(cause) => {
+            let cause = format!("Error transforming docs: {}", cause);
+            return Err(reject_docs(docs, &cause, writer, indexing));
+        }
+    };
+    match posted {
+        Ok(_) => Ok(()),
+        Err(cause) if indexing.bisect && cause.is_rejected() => match split_in_halves(docs) {
+            None => Err(reject_docs(docs, &cause.to_string(), writer, indexing)),
+            Some((left, right)) => {
+                debug!("Bisecting docs of {} rejected by solr in thread #{}", docs.entry, writer);
+                match post_or_bisect(&left, url, writer, client, indexing) {
+                    Err(true) => Err(true),
+                    first => post_or_bisect(&right, url, writer, client, indexing).and(first),
+                }
+            }
+        },
+        Err(cause) => Err(reject_docs(docs, &cause.to_string(), writer, indexing)),
+    }
+}
+
+/// Reports the docs not indexed returning true when `max_errors` was exceeded
+fn reject_docs(docs: &Docs, cause: &str, writer: usize, indexing: &Indexing) -> bool {
+    let current = indexing.error_count.fetch_add(1, Ordering::SeqCst);
+    error!(
+        "Error #{}/{} in thread #{} when indexing solr core:\n{}{:?}",
+        current, indexing.max_errors, writer, cause, docs
+    );
+    if let Some(dead_letters) = &indexing.dead_letters {
+        match dead_letters.store(docs, cause) {
+            Ok(stored) => info!("Stored the docs rejected by solr in: {}", stored),
+            Err(fail) => error!("Error in thread #{} storing rejected docs: {}", writer, fail),
+        }
+    }
+    current > indexing.max_errors
+}
+
+/// Splits the json array of docs in two parts. None when there is a single doc.
+fn split_in_halves(docs: &Docs) -> Option<(Docs, Docs)> {
+    let parsed: Vec<&RawValue> = serde_json::from_str(&docs.json).ok()?;
+    if parsed.len() < 2 {
+        return None;
+    }
+    let (first, second) = parsed.split_at(parsed.len() / 2);
+    let half = |part: &[&RawValue]| Docs {
+        json: serde_json::to_string(part).unwrap(),
+        archive: docs.archive.clone(),
+        entry: docs.entry.clone(),
+    };
+    Some((half(first), half(second)))
 }
 
 // endregion
 
 #[cfg(test)]
 mod tests {
-    use crate::{args::*, fails::*};
+    use crate::{args::*, fails::*, ingest::Docs, restore::split_in_halves};
 
     impl Arguments {
         pub fn put(&self) -> BoxedResult<&Restore> {
             match &self {
-                Self::Restore(puts) => Ok(&puts),
+                Self::Restore(puts) => Ok(puts),
                 _ => raise("command must be 'restore' !"),
             }
         }
     }
 
+    #[test]
+    fn check_split_in_halves() {
+        let docs = Docs {
+            json: r#"[{"id":1},{"id":2},{"id":3}]"#.to_string(),
+            archive: "a.zip".to_string(),
+            entry: "docs_at_000000001.json".to_string(),
+        };
+        let (first, second) = split_in_halves(&docs).unwrap();
+        assert_eq!(first.json, r#"[{"id":1}]"#);
+        assert_eq!(second.json, r#"[{"id":2},{"id":3}]"#);
+        assert_eq!(second.entry, docs.entry);
+        assert!(split_in_halves(&first).is_none());
+    }
+
     #[test]
     fn check_restore_pattern() {
         let parsed = Arguments::mockup_args_restore();
         let puts = parsed.put().unwrap();
         let wilcard = puts.get_pattern();
-        assert_eq!(wilcard.ends_with(".zip"), true);
+        assert!(wilcard.ends_with(".zip"));
     }
 
     #[test]
@@ -304,7 +549,7 @@ mod tests {
         for zip in puts.find_archives().unwrap() {
             println!("{:?}", zip);
             let path = zip.to_str().unwrap();
-            assert_eq!(path.ends_with(".zip"), true);
+            assert!(path.ends_with(".zip"));
         }
     }
 }
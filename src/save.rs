@@ -1,83 +1,127 @@
-use log::error;
+use chrono::Utc;
+use log::{error, trace};
+use serde::Serialize;
+use serde_json::value::RawValue;
 use zip::{result::ZipResult, write::FileOptions, ZipWriter};
 
 use std::{
-    io::Write,
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Write},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-use crate::steps::Documents;
+use crate::{
+    args::ArchiveFormat,
+    fails::*,
+    helpers::*,
+    ingest::Docs,
+    journal::{Journal, JournalEntry},
+    manifest::{file_sha256, ArchiveSummary, EntrySummary, Manifest},
+    steps::Documents,
+};
 
 // TODO: split in multiple files of constant size
 // TODO: limit file size based on zip.stats.bytes_written
 
 // region Archiver
 
-type Compressor = ZipWriter<std::fs::File>;
+type Compressor = ZipWriter<File>;
 
-pub struct Archiver {
+pub struct Archiver<'a> {
     writer: Option<Compressor>,
     folder: PathBuf,
     file_pattern: String,
     max_files: usize,
     file_count: usize,
+    archive_name: String,
+    journal: Arc<Journal>,
+    pending: Vec<JournalEntry>,
+    entries: Vec<EntrySummary>,
+    manifest: &'a Manifest,
+    format: ArchiveFormat,
 }
 
-impl Archiver {
-    pub fn write_on(output_dir: &PathBuf, output_pattern: &str, max: usize) -> Self {
+impl<'a> Archiver<'a> {
+    pub fn write_on(
+        output_dir: &Path, output_pattern: &str, max: usize, journal: Arc<Journal>,
+        manifest: &'a Manifest,
+    ) -> Self {
         Archiver {
             writer: None,
             folder: output_dir.to_owned(),
             file_pattern: output_pattern.to_string(),
             max_files: max,
             file_count: 0,
+            archive_name: EMPTY_STRING,
+            journal,
+            pending: Vec::new(),
+            entries: Vec::new(),
+            manifest,
+            format: ArchiveFormat::Json,
         }
     }
 
+    pub fn with_format(mut self, format: ArchiveFormat) -> Self {
+        self.format = format;
+        self
+    }
+
     fn create_archive(&mut self, suffix: &str) -> ZipResult<()> {
         self.close_archive()?;
 
-        let file_name = self.file_pattern.replace("{}", suffix);
-        let zip_path = Path::new(&self.folder);
-        let zip_name = Path::new(&file_name);
-        let zip_file = zip_path.join(&zip_name);
-
-        let file = std::fs::File::create(&zip_file)?;
-        let zip = zip::ZipWriter::new(file);
+        let (zip, file_name) = create_new_zip(&self.folder, &self.file_pattern, suffix)?;
 
         self.writer = Some(zip);
+        self.archive_name = file_name;
         self.file_count = 0;
         Ok(())
     }
 
     fn write_file(&mut self, filename: &str, docs: &str) -> ZipResult<()> {
-        let bytes = docs.as_bytes();
-
         let zip = self.writer.as_mut().unwrap();
-
-        let opts: FileOptions = FileOptions::default()
-            .compression_method(zip::CompressionMethod::Deflated)
-            .unix_permissions(0o644);
-
-        zip.start_file(filename, opts)?;
-        zip.write_all(bytes)?;
-        zip.flush()?;
-        Ok(())
+        write_zip_entry(zip, filename, docs)
     }
 
+    /// Finishes the current archive and records it into the journal and the manifest
     pub fn close_archive(&mut self) -> ZipResult<()> {
-        if self.writer.is_some() {
-            self.writer.as_mut().unwrap().finish()?;
+        if let Some(zip) = self.writer.as_mut() {
+            zip.finish()?;
+            self.journal.record(&self.pending)?;
+
+            let zip_file = self.folder.join(&self.archive_name);
+            let entries = std::mem::take(&mut self.entries);
+            self.manifest.add_archive(ArchiveSummary {
+                name: self.archive_name.clone(),
+                sha256: file_sha256(&zip_file)?,
+                docs: entries.iter().map(|entry| entry.docs).sum(),
+                entries,
+            });
         }
         self.writer = None;
+        self.pending.clear();
+        self.entries.clear();
         Ok(())
     }
 
     pub fn write_documents(&mut self, docs: &Documents) -> ZipResult<()> {
         let json = &docs.docs;
         let step = &docs.step;
+        if self.journal.is_page_done(step) {
+            // the pages of a chain already archived before being interrupted are fetched again on resume
+            trace!("Skipping page already archived: {} at {}", step.key, step.curr);
+            return Ok(());
+        }
 
-        let filename = format!("docs_at_{:09}.json", step.curr + 1);
+        let (filename, contents) = match self.format {
+            ArchiveFormat::Json => (step.get_docs_filename(), Cow::Borrowed(json.as_str())),
+            ArchiveFormat::Jsonl => {
+                let lines = to_json_lines(json)
+                    .map_err(|cause| io::Error::new(ErrorKind::InvalidData, cause))?;
+                (format!("{}l", step.get_docs_filename()), Cow::Owned(lines))
+            }
+        };
 
         self.file_count += 1;
         let wrap = self.file_count >= self.max_files;
@@ -86,11 +130,20 @@ impl Archiver {
             let suffix = format!("{:09}", step.curr + 1);
             self.create_archive(&suffix)?;
         }
-        self.write_file(&filename, &json)
+        self.write_file(&filename, &contents)?;
+
+        self.pending.push(JournalEntry {
+            archive: self.archive_name.clone(),
+            step: step.key.clone(),
+            curr: step.curr,
+            last: docs.last_page,
+        });
+        self.entries.push(EntrySummary { name: filename, docs: docs.count });
+        Ok(())
     }
 }
 
-impl Drop for Archiver {
+impl Drop for Archiver<'_> {
     fn drop(&mut self) {
         let fail = self.close_archive();
         if let Err(cause) = fail {
@@ -98,4 +151,184 @@ impl Drop for Archiver {
         }
     }
 }
+
+/// Rewrites the json array of docs with one doc per line as in `jq -c '.[]'`
+fn to_json_lines(json: &str) -> Result<String, serde_json::Error> {
+    let parsed: Vec<&RawValue> = serde_json::from_str(json)?;
+    let mut lines = String::with_capacity(json.len());
+    for doc in parsed {
+        // raw line breaks can only be whitespace between tokens in valid json
+        lines.push_str(&doc.get().replace(['\r', '\n'], " "));
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+/// Creates a zip file never overwriting a existing archive as it may hold docs recorded in the journal
+fn create_new_zip(
+    folder: &Path, file_pattern: &str, suffix: &str,
+) -> ZipResult<(Compressor, String)> {
+    let mut attempt = 0;
+    loop {
+        let numbered =
+            if attempt == 0 { suffix.to_string() } else { format!("{}_{}", suffix, attempt) };
+        let file_name = file_pattern.replace(BRACKETS, &numbered);
+        let zip_file = folder.join(&file_name);
+
+        match OpenOptions::new().write(true).create_new(true).open(&zip_file) {
+            Ok(file) => break Ok((ZipWriter::new(file), file_name)),
+            Err(cause) if cause.kind() == ErrorKind::AlreadyExists => attempt += 1,
+            Err(cause) => break Err(cause.into()),
+        }
+    }
+}
+
+fn write_zip_entry(zip: &mut Compressor, filename: &str, docs: &str) -> ZipResult<()> {
+    let opts: FileOptions = FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .unix_permissions(0o644);
+
+    zip.start_file(filename, opts)?;
+    zip.write_all(docs.as_bytes())?;
+    zip.flush()?;
+    Ok(())
+}
+
+// endregion
+
+// region Dead letters
+
+/// Stores the batches rejected by Solr in zip files with the same layout of a backup.
+/// The error of each batch is written in a `.errors.jsonl` file next to the zip files.
+pub struct DeadLetters {
+    folder: PathBuf,
+    file_pattern: String,
+    errors_path: PathBuf,
+    max_files: usize,
+    state: Mutex<Rejected>,
+}
+
+#[derive(Default)]
+struct Rejected {
+    writer: Option<Compressor>,
+    archive_name: String,
+    file_count: usize,
+    archive_count: usize,
+    batch_count: usize,
+    errors: Option<File>,
+}
+
+#[derive(Serialize)]
+struct RejectedEntry<'a> {
+    archive: &'a str,
+    entry: &'a str,
+    source_archive: &'a str,
+    source_entry: &'a str,
+    error: &'a str,
+}
+
+impl DeadLetters {
+    pub fn write_on(output_dir: &Path, core: &str, max: usize) -> Self {
+        let time = Utc::now().format("%Y%m%d_%H%M%S");
+        let prefix = format!("{}_rejected_at_{}", core, time);
+        DeadLetters {
+            folder: output_dir.to_owned(),
+            file_pattern: format!("{}_seq_{}.zip", prefix, BRACKETS),
+            errors_path: output_dir.join(format!("{}.errors.jsonl", prefix)),
+            max_files: max,
+            state: Mutex::new(Rejected::default()),
+        }
+    }
+
+    /// Writes the docs as a new entry and appends the error message to the errors file
+    pub fn store(&self, docs: &Docs, cause: &str) -> BoxedResult<String> {
+        let mut rejected = self.state.lock().unwrap();
+        if rejected.writer.is_none() || rejected.file_count >= self.max_files {
+            if let Some(mut zip) = rejected.writer.take() {
+                zip.finish()?;
+            }
+            rejected.archive_count += 1;
+            let suffix = format!("{:03}", rejected.archive_count);
+            let (zip, file_name) = create_new_zip(&self.folder, &self.file_pattern, &suffix)?;
+            rejected.writer = Some(zip);
+            rejected.archive_name = file_name;
+            rejected.file_count = 0;
+        }
+        rejected.file_count += 1;
+        rejected.batch_count += 1;
+        let entry = format!("docs_at_{:09}.json", rejected.batch_count);
+        write_zip_entry(rejected.writer.as_mut().unwrap(), &entry, &docs.json)?;
+
+        let line = RejectedEntry {
+            archive: &rejected.archive_name,
+            entry: &entry,
+            source_archive: &docs.archive,
+            source_entry: &docs.entry,
+            error: cause,
+        };
+        let mut text = serde_json::to_string(&line)?;
+        text.push('\n');
+        if rejected.errors.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&self.errors_path)?;
+            rejected.errors = Some(file);
+        }
+        let errors = rejected.errors.as_mut().unwrap();
+        errors.write_all(text.as_bytes())?;
+        errors.flush()?;
+        Ok(format!("{}/{}", rejected.archive_name, entry))
+    }
+}
+
+impl Drop for DeadLetters {
+    fn drop(&mut self) {
+        let rejected = self.state.get_mut().unwrap();
+        if let Some(mut zip) = rejected.writer.take() {
+            if let Err(cause) = zip.finish() {
+                error!("> Dropping {}", cause);
+            }
+        }
+    }
+}
+
 // endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::{ingest::*, save::*};
+    use std::fs;
+
+    fn rejected(entry: &str) -> Docs {
+        Docs {
+            json: r#"[{"id":1}]"#.to_string(),
+            archive: "a.zip".to_string(),
+            entry: entry.to_string(),
+        }
+    }
+
+    #[test]
+    fn check_dead_letters_store() {
+        let dir = std::env::temp_dir().join("solrcopy_check_dead_letters");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+
+        let dead_letters = DeadLetters::write_on(&dir, "demo", 2);
+        let first = dead_letters.store(&rejected("docs_at_000000001.json"), "bad doc").unwrap();
+        dead_letters.store(&rejected("docs_at_000000002.json"), "bad doc").unwrap();
+        let third = dead_letters.store(&rejected("docs_at_000000003.json"), "bad doc").unwrap();
+        let errors_path = dead_letters.errors_path.clone();
+        drop(dead_letters);
+
+        assert!(first.ends_with("_seq_001.zip/docs_at_000000001.json"));
+        assert!(third.ends_with("_seq_002.zip/docs_at_000000003.json"));
+
+        let zip_name = first.split('/').next().unwrap();
+        let entries = ArchiveReader::create_reader(&dir.join(zip_name)).unwrap().count();
+        assert_eq!(entries, 2);
+
+        let errors = fs::read_to_string(errors_path).unwrap();
+        assert_eq!(errors.lines().count(), 3);
+        assert!(errors.contains(r#""source_entry":"docs_at_000000002.json","error":"bad doc""#));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use glob::glob;
+use log::{debug, info, warn};
+use serde_json::{Map, Value};
+
+use std::{collections::HashSet, fs, path::PathBuf};
+
+use crate::{
+    args::{Backup, Restore},
+    connection::SolrClient,
+    fails::*,
+};
+
+const SCHEMA_FILE: &str = "schema.json";
+
+/// Files saved in the `.schema` folder and the handlers of the core they are read from
+const SCHEMA_FILES: [(&str, &str); 3] = [
+    (SCHEMA_FILE, "/schema?wt=json"),
+    ("configoverlay.json", "/config/overlay?wt=json"),
+    ("params.json", "/config/params?wt=json"),
+];
+
+/// Sections of the schema with named definitions and the Schema API command for adding them
+const SCHEMA_SECTIONS: [(&str, &str); 3] = [
+    ("fieldTypes", "add-field-type"),
+    ("fields", "add-field"),
+    ("dynamicFields", "add-dynamic-field"),
+];
+
+// region Capture
+
+impl Backup {
+    pub fn get_schema_dir(&self, prefix: &str) -> PathBuf {
+        self.dir.join(format!("{}.schema", prefix))
+    }
+
+    /// Saves the schema, the config overlay and the request params of the core next to the archives
+    pub fn capture_schema(&self, prefix: &str) -> BoxedResult<()> {
+        let schema_dir = self.get_schema_dir(prefix);
+        fs::create_dir_all(&schema_dir)?;
+        for (file_name, handler) in &SCHEMA_FILES {
+            let url = self.options.get_core_handler_url(handler);
+            debug!("Saving {} of core {} from: {}", file_name, self.options.core, url);
+            match SolrClient::query_get_as_text(&url) {
+                Ok(json) => fs::write(schema_dir.join(file_name), json)?,
+                Err(cause) if *file_name != SCHEMA_FILE => {
+                    warn!("Cannot save {} of core {}: {}", file_name, self.options.core, cause)
+                }
+                Err(cause) => {
+                    return throw(format!(
+                        "Cannot save the schema of core {}: {}",
+                        self.options.core, cause
+                    ))
+                }
+            }
+        }
+        info!("Saved the schema of core {} in {:?}", self.options.core, schema_dir);
+        Ok(())
+    }
+}
+
+// endregion
+
+// region Apply
+
+/// Adds to the core the definitions of the saved schema that it lacks
+pub(crate) fn apply_schema_main(params: &Restore) -> BoxedError {
+    let core = &params.options.core;
+    let saved_path = params.find_saved_schema()?;
+    info!("Applying the schema saved in {:?} into core {}", saved_path, core);
+
+    let saved: Value = serde_json::from_str(&fs::read_to_string(&saved_path)?)?;
+    let schema_url = params.options.get_core_handler_url("/schema?wt=json");
+    let current: Value = serde_json::from_str(&SolrClient::query_get_as_text(&schema_url)?)?;
+
+    let commands = get_missing_commands(&saved, &current);
+    if commands.is_empty() {
+        info!("The schema of core {} already has all the saved definitions.", core);
+        return Ok(());
+    }
+    for (command, definitions) in &commands {
+        info!("  {}: {} definitions", command, definitions.as_array().map_or(0, Vec::len));
+    }
+    let body = Value::Object(commands).to_string();
+    let response = SolrClient::send_post_as_json(&schema_url, &body)?;
+    let parsed: Value = serde_json::from_str(&response)?;
+    match parsed.get("errors") {
+        Some(errors) => throw(format!("Failed applying the schema into core {}: {}", core, errors)),
+        None => Ok(()),
+    }
+}
+
+impl Restore {
+    /// Finds the newest schema saved along the archives matching the search pattern
+    fn find_saved_schema(&self) -> BoxedResult<PathBuf> {
+        let pattern = self.get_pattern();
+        let stem = pattern.trim_end_matches(".zip").trim_end_matches('*');
+        let wilcard = format!("{}*.schema/{}", stem, SCHEMA_FILE);
+        let mut found = glob(&wilcard)?.filter_map(Result::ok).collect::<Vec<_>>();
+        found.sort_unstable();
+        match found.pop() {
+            None => throw(format!("Found no schema saved by backup --with-schema in: {}", wilcard)),
+            Some(path) => Ok(path),
+        }
+    }
+}
+
+/// Builds the Schema API commands adding the field types, fields and copy fields missing in the core
+fn get_missing_commands(saved: &Value, current: &Value) -> Map<String, Value> {
+    let mut commands = Map::new();
+    for (section, command) in &SCHEMA_SECTIONS {
+        let existing: HashSet<&str> =
+            get_section(current, section).iter().filter_map(|def| def["name"].as_str()).collect();
+        let missing: Vec<Value> = get_section(saved, section)
+            .iter()
+            .filter(|def| def["name"].as_str().is_some_and(|name| !existing.contains(name)))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            commands.insert(command.to_string(), Value::Array(missing));
+        }
+    }
+    let copy_key = |def: &Value| (def["source"].to_string(), def["dest"].to_string());
+    let existing: HashSet<(String, String)> =
+        get_section(current, "copyFields").iter().map(copy_key).collect();
+    let missing: Vec<Value> = get_section(saved, "copyFields")
+        .iter()
+        .filter(|def| !existing.contains(&copy_key(def)))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        commands.insert("add-copy-field".to_string(), Value::Array(missing));
+    }
+    commands
+}
+
+fn get_section<'a>(schema: &'a Value, section: &str) -> &'a [Value] {
+    schema["schema"][section].as_array().map_or(&[], Vec::as_slice)
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::*;
+
+    #[test]
+    fn check_missing_schema_commands() {
+        let saved: Value = serde_json::from_str(
+            r#"{"schema":{"uniqueKey":"id",
+            "fieldTypes":[{"name":"string","class":"solr.StrField"},{"name":"plong","class":"solr.LongPointField"}],
+            "fields":[{"name":"id","type":"string"},{"name":"views","type":"plong","stored":true}],
+            "dynamicFields":[{"name":"*_s","type":"string"}],
+            "copyFields":[{"source":"id","dest":"id_s"}]}}"#,
+        )
+        .unwrap();
+        let current: Value = serde_json::from_str(
+            r#"{"schema":{"fieldTypes":[{"name":"string","class":"solr.StrField"}],
+            "fields":[{"name":"id","type":"string"}]}}"#,
+        )
+        .unwrap();
+
+        let commands = get_missing_commands(&saved, &current);
+        let names: Vec<&str> = commands.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            vec!["add-field-type", "add-field", "add-dynamic-field", "add-copy-field"]
+        );
+        assert_eq!(commands["add-field"][0]["name"], "views");
+        assert_eq!(commands["add-field-type"].as_array().unwrap().len(), 1);
+
+        assert!(get_missing_commands(&saved, &saved).is_empty());
+    }
+}
@@ -0,0 +1,386 @@
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::Value;
+
+use std::collections::HashMap;
+
+use crate::{
+    args::IterateMode,
+    connection::SolrClient,
+    fails::*,
+    helpers::*,
+    steps::{
+        format_solr_time, round_to_period, to_utc_time, Querying, SliceItem, Slices, SolrCore,
+    },
+};
+
+// region Slicing bounds
+
+/// Min and max values of the field used for slicing the query
+#[derive(Debug, PartialEq)]
+enum FieldBounds {
+    Dates(NaiveDateTime, NaiveDateTime),
+    Numbers(usize, usize),
+}
+
+#[derive(Deserialize, Debug)]
+struct StatsResponse {
+    stats: StatsFields,
+}
+
+#[derive(Deserialize, Debug)]
+struct StatsFields {
+    stats_fields: HashMap<String, FieldStats>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FieldStats {
+    min: Value,
+    max: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct FacetResponse {
+    facet_counts: FacetCounts,
+}
+
+#[derive(Deserialize, Debug)]
+struct FacetCounts {
+    facet_ranges: HashMap<String, FacetRange>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FacetRange {
+    counts: Vec<Value>,
+}
+
+impl Querying<'_> {
+    /// The slices for iterating the query. With `--iterate-field` the range is found with the stats
+    /// of the field in the docs of the query and the slices without docs are skipped.
+    pub fn plan_slices(&self) -> BoxedResult<Slices<String>> {
+        let field = match &self.source.iterate_field {
+            None => return self.get_slices(),
+            Some(name) => name.as_str(),
+        };
+        let stats_url = self.get_unsliced_url(&format!("&rows=0&stats=true&stats.field={}", field));
+        debug!("Finding the range of field {} at: {}", field, stats_url);
+        let bounds = FieldBounds::parse(&SolrClient::query_get_as_text(&stats_url)?, field)?;
+
+        let zone = self.get_time_zone()?;
+        let mut slices =
+            bounds.to_slices(self.source.iterate_by, self.source.iterate_step, zone)?;
+        info!("Slicing the query by {} between {} and {}", field, slices.curr, slices.end);
+
+        let facet_url = self.get_unsliced_url(&bounds.get_facet_params(field, &slices)?);
+        debug!("Counting the docs of each slice at: {}", facet_url);
+        let counts = parse_range_counts(&SolrClient::query_get_as_text(&facet_url)?, field)?;
+
+        if let Some(target) = self.source.slice_docs {
+            let counted = bounds.count_slices(&slices, &counts);
+            let split = split_slices(counted, target, |slice| {
+                let count_url = self.get_slice_count_url(&bounds.to_item(slice));
+                Ok(SolrCore::parse_num_found(&SolrClient::query_get_as_text(&count_url)?)?)
+            })?;
+            let merged = merge_slices(split, target);
+            info!("Planned {} slices holding about {} docs each", merged.len(), target);
+            slices.planned = merged.iter().map(|slice| bounds.to_item(slice)).collect();
+            return Ok(slices);
+        }
+        slices.skipping =
+            counts.into_iter().filter(|(_, count)| *count == 0).map(|(begin, _)| begin).collect();
+        if !slices.skipping.is_empty() {
+            info!("Skipping {} slices without docs", slices.skipping.len());
+        }
+        Ok(slices)
+    }
+}
+
+impl FieldBounds {
+    fn parse(json: &str, field: &str) -> BoxedResult<Self> {
+        let mut parsed: StatsResponse = serde_json::from_str(json)?;
+        let stats = match parsed.stats.stats_fields.remove(field) {
+            None => return throw(format!("Solr returned no stats for the field {}", field)),
+            Some(found) => found,
+        };
+        if stats.min.is_null() || stats.max.is_null() {
+            return throw(format!("Found no docs with values in the field {}", field));
+        }
+        if let (Some(min), Some(max)) = (parse_date(&stats.min), parse_date(&stats.max)) {
+            return Ok(FieldBounds::Dates(min, max));
+        }
+        if let (Some(min), Some(max)) = (parse_number(&stats.min), parse_number(&stats.max)) {
+            return Ok(FieldBounds::Numbers(min, max));
+        }
+        throw(format!("The field {} must hold dates or positive integers for slicing", field))
+    }
+
+    /// Slices covering the values of the field. Integer fields are always sliced by `range`.
+    /// Dates are sliced in the time zone starting on the boundary of the week, month or year.
+    fn to_slices(
+        &self, mode: IterateMode, increment: usize, zone: Tz,
+    ) -> BoxedResult<Slices<String>> {
+        let (curr, end, mode) = match self {
+            FieldBounds::Numbers(min, max) => {
+                (min.to_string(), (max + 1).to_string(), IterateMode::Range)
+            }
+            FieldBounds::Dates(min, max) => {
+                if mode == IterateMode::Range || mode == IterateMode::None {
+                    return raise(
+                        "Dates must be sliced with --iterate-by minute, hour, day, week, month or year",
+                    );
+                }
+                let first = round_to_period(zone.from_utc_datetime(min).naive_local(), mode);
+                let last = zone.from_utc_datetime(max).naive_local() + Duration::seconds(1);
+                (format_between_date(first), format_between_date(last), mode)
+            }
+        };
+        let (skipping, planned) = (Vec::new(), Vec::new());
+        Ok(Slices { curr, end, increment, mode, skipping, planned, zone })
+    }
+
+    /// Params for counting with range facets the docs in each slice
+    fn get_facet_params(&self, field: &str, slices: &Slices<String>) -> BoxedResult<String> {
+        let to_utc = |local: &str| -> BoxedResult<String> {
+            Ok(format_solr_time(to_utc_time(slices.zone, local.parse::<NaiveDateTime>()?)))
+        };
+        let (start, end, gap) = match self {
+            FieldBounds::Numbers(..) => {
+                (slices.curr.clone(), slices.end.clone(), slices.increment.to_string())
+            }
+            FieldBounds::Dates(..) => {
+                let (amount, unit) = match slices.mode {
+                    IterateMode::Minute => (slices.increment, "MINUTES"),
+                    IterateMode::Hour => (slices.increment, "HOURS"),
+                    IterateMode::Week => (slices.increment * 7, "DAYS"),
+                    IterateMode::Month => (slices.increment, "MONTHS"),
+                    IterateMode::Year => (slices.increment, "YEARS"),
+                    _ => (slices.increment, "DAYS"),
+                };
+                (to_utc(&slices.curr)?, to_utc(&slices.end)?, format!("%2B{}{}", amount, unit))
+            }
+        };
+        Ok(format!(
+            "&rows=0&facet=true&facet.mincount=0&json.nl=flat&facet.range={}&facet.range.start={}&facet.range.end={}&facet.range.gap={}",
+            field, start, end, gap
+        ))
+    }
+
+    /// The values of the field as seconds of the dates or the numbers themselves
+    fn to_value(&self, text: &str) -> Option<i64> {
+        match self {
+            FieldBounds::Numbers(..) => text.parse::<i64>().ok(),
+            FieldBounds::Dates(..) => {
+                parse_date(&Value::from(text)).map(|d| d.and_utc().timestamp())
+            }
+        }
+    }
+
+    fn to_text(&self, value: i64) -> String {
+        match self {
+            FieldBounds::Numbers(..) => value.to_string(),
+            FieldBounds::Dates(..) => match DateTime::from_timestamp(value, 0) {
+                Some(date) => format_solr_time(date.naive_utc()),
+                None => value.to_string(),
+            },
+        }
+    }
+
+    fn to_item(&self, slice: &Counted) -> SliceItem {
+        SliceItem { begin: self.to_text(slice.begin), end: self.to_text(slice.end) }
+    }
+
+    /// Pairs the slices of fixed size with the count of docs found by the range facets
+    fn count_slices(&self, slices: &Slices<String>, counts: &[(String, usize)]) -> Vec<Counted> {
+        let found: HashMap<&str, usize> =
+            counts.iter().map(|(begin, count)| (begin.as_str(), *count)).collect();
+        slices
+            .get_iterator()
+            .filter_map(|item| {
+                let begin = self.to_value(&item.begin)?;
+                let end = self.to_value(&item.end)?;
+                let count = found.get(item.begin.as_str()).copied().unwrap_or(0);
+                Some(Counted { begin, end, count })
+            })
+            .collect()
+    }
+}
+
+// endregion
+
+// region Adaptive slices
+
+/// A slice between the values `begin` and `end` (inclusive) holding `count` docs
+#[derive(Debug, PartialEq)]
+struct Counted {
+    begin: i64,
+    end: i64,
+    count: usize,
+}
+
+/// Splits in halves the slices holding more docs than the `target`, counting the docs of each half
+fn split_slices<F>(slices: Vec<Counted>, target: usize, mut count: F) -> BoxedResult<Vec<Counted>>
+where
+    F: FnMut(&Counted) -> BoxedResult<usize>,
+{
+    let mut res = Vec::with_capacity(slices.len());
+    let mut pending = slices;
+    pending.reverse();
+    while let Some(slice) = pending.pop() {
+        if slice.count <= target || slice.begin >= slice.end {
+            res.push(slice);
+            continue;
+        }
+        let middle = slice.begin + (slice.end - slice.begin) / 2;
+        let mut left = Counted { begin: slice.begin, end: middle, count: 0 };
+        left.count = count(&left)?;
+        let right = Counted {
+            begin: middle + 1,
+            end: slice.end,
+            count: slice.count.saturating_sub(left.count),
+        };
+        pending.push(right);
+        pending.push(left);
+    }
+    Ok(res)
+}
+
+/// Joins the consecutive slices while they hold no more docs than the `target` and drops the empty ones
+fn merge_slices(slices: Vec<Counted>, target: usize) -> Vec<Counted> {
+    let mut res: Vec<Counted> = Vec::new();
+    for slice in slices.into_iter().filter(|slice| slice.count > 0) {
+        match res.last_mut() {
+            Some(last) if last.count + slice.count <= target => {
+                last.end = slice.end;
+                last.count += slice.count;
+            }
+            _ => res.push(slice),
+        }
+    }
+    res
+}
+
+// endregion
+
+// region Slicing helpers
+
+/// Dates in Solr responses like `2020-04-01T10:00:00Z` or with milliseconds
+fn parse_date(value: &Value) -> Option<NaiveDateTime> {
+    let text = value.as_str()?;
+    DateTime::parse_from_rfc3339(text).ok().map(|parsed| parsed.naive_utc())
+}
+
+/// Solr answers the stats of integer fields as floats like `12.0`
+fn parse_number(value: &Value) -> Option<usize> {
+    if let Some(num) = value.as_u64() {
+        return Some(num.to_usize());
+    }
+    let num = value.as_f64()?;
+    if num >= 0.0 && num.fract() == 0.0 {
+        Some(num as usize)
+    } else {
+        None
+    }
+}
+
+/// Formats the dates like the values accepted in `--between`
+fn format_between_date(date_time: NaiveDateTime) -> String {
+    date_time.format("%Y-%m-%dT%H:%M:%S").to_string()
+}
+
+/// Returns the begin of each range with the count of docs in the response of range facets
+fn parse_range_counts(json: &str, field: &str) -> BoxedResult<Vec<(String, usize)>> {
+    let mut parsed: FacetResponse = serde_json::from_str(json)?;
+    let range = match parsed.facet_counts.facet_ranges.remove(field) {
+        None => return throw(format!("Solr returned no range facets for the field {}", field)),
+        Some(found) => found,
+    };
+    let counts = range
+        .counts
+        .chunks(2)
+        .filter_map(|pair| {
+            let text = pair.first()?.as_str()?;
+            let count = pair.get(1)?.as_u64()?.to_usize();
+            let begin = match parse_date(&pair[0]) {
+                Some(date) => format_solr_time(date),
+                None => text.to_string(),
+            };
+            Some((begin, count))
+        })
+        .collect();
+    Ok(counts)
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::{args::IterateMode, slicing::*};
+
+    #[test]
+    fn check_slices_from_field_stats() {
+        let dates = r#"{"stats":{"stats_fields":{"date":{"min":"2020-04-01T00:00:00Z","max":"2020-04-03T11:12:13.250Z","count":8}}}}"#;
+        let bounds = FieldBounds::parse(dates, "date").unwrap();
+        let mut slices = bounds.to_slices(IterateMode::Day, 1, Tz::UTC).unwrap();
+        assert_eq!(slices.end, "2020-04-03T11:12:14");
+        assert!(bounds.to_slices(IterateMode::Range, 1, Tz::UTC).is_err());
+
+        let params = bounds.get_facet_params("date", &slices).unwrap();
+        assert!(params.ends_with("&facet.range.start=2020-04-01T00:00:00Z&facet.range.end=2020-04-03T11:12:14Z&facet.range.gap=%2B1DAYS"));
+
+        let facets = r#"{"facet_counts":{"facet_ranges":{"date":{"counts":["2020-04-01T00:00:00Z",5,"2020-04-02T00:00:00Z",0,"2020-04-03T00:00:00Z",3],"gap":"+1DAYS"}}}}"#;
+        let counts = parse_range_counts(facets, "date").unwrap();
+        assert_eq!(counts[1], ("2020-04-02T00:00:00Z".to_string(), 0));
+        slices.skipping = vec![counts[1].0.clone()];
+        let begins: Vec<String> = slices.get_iterator().map(|item| item.begin).collect();
+        assert_eq!(begins, vec!["2020-04-01T00:00:00Z", "2020-04-03T00:00:00Z"]);
+        assert_eq!(slices.estimate_steps().unwrap(), 2);
+
+        let numbers = r#"{"stats":{"stats_fields":{"num":{"min":10.0,"max":29.0}}}}"#;
+        let bounds = FieldBounds::parse(numbers, "num").unwrap();
+        assert_eq!(bounds, FieldBounds::Numbers(10, 29));
+        let slices = bounds.to_slices(IterateMode::Day, 10, Tz::UTC).unwrap();
+        assert_eq!((slices.curr.as_str(), slices.end.as_str()), ("10", "30"));
+        assert_eq!(slices.mode, IterateMode::Range);
+
+        let months = FieldBounds::Dates(
+            "2020-01-15T10:00:00".parse().unwrap(),
+            "2020-03-02T00:00:00".parse().unwrap(),
+        );
+        let zone = "America/Sao_Paulo".parse::<Tz>().unwrap();
+        let slices = months.to_slices(IterateMode::Month, 1, zone).unwrap();
+        assert_eq!(slices.curr, "2020-01-01T00:00:00");
+        let params = months.get_facet_params("date", &slices).unwrap();
+        assert!(params.contains("&facet.range.start=2020-01-01T03:00:00Z&"));
+        assert!(params.ends_with("&facet.range.gap=%2B1MONTHS"));
+
+        let empty = r#"{"stats":{"stats_fields":{"num":{"min":null,"max":null,"count":0}}}}"#;
+        assert!(FieldBounds::parse(empty, "num").is_err());
+    }
+
+    #[test]
+    fn check_adaptive_slices() {
+        let slice = |begin: i64, end: i64, count: usize| Counted { begin, end, count };
+        // docs evenly spread by 1 doc per value
+        let counter = |part: &Counted| Ok((part.end - part.begin + 1) as usize);
+
+        let fixed = vec![slice(0, 9, 3), slice(10, 19, 0), slice(20, 29, 4), slice(30, 69, 40)];
+        let split = split_slices(fixed, 10, counter).unwrap();
+        assert_eq!(split.len(), 7);
+        assert_eq!(split[3], slice(30, 39, 10));
+        assert!(split.iter().skip(3).all(|part| part.count <= 10));
+
+        let merged = merge_slices(split, 10);
+        let bounds: Vec<(i64, i64)> = merged.iter().map(|part| (part.begin, part.end)).collect();
+        assert_eq!(bounds, vec![(0, 29), (30, 39), (40, 49), (50, 59), (60, 69)]);
+
+        let dates = FieldBounds::Dates(NaiveDateTime::default(), NaiveDateTime::default());
+        let item = dates.to_item(&slice(1585699200, 1585785599, 1));
+        assert_eq!(
+            (item.begin.as_str(), item.end.as_str()),
+            ("2020-04-01T00:00:00Z", "2020-04-01T23:59:59Z")
+        );
+        assert_eq!(dates.to_value(&item.begin), Some(1585699200));
+    }
+}
@@ -1,7 +1,15 @@
-use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, Months, NaiveDate, NaiveDateTime, TimeZone,
+    Timelike, Utc,
+};
+use chrono_tz::Tz;
+use glob::glob;
+use url::form_urlencoded::byte_serialize;
+
+use std::{convert::TryFrom, path::PathBuf};
 
 use crate::{
-    args::{Backup, IterateMode},
+    args::{Backup, CommonArgs, IterateMode, ParallelArgs, QueryArgs},
     fails::*,
     helpers::*,
 };
@@ -14,9 +22,15 @@ pub struct Slices<T> {
     pub end: T,
     pub increment: usize,
     pub mode: IterateMode,
+    /// Begin of the slices known to have no docs
+    pub skipping: Vec<String>,
+    /// Slices sized by counting their docs, used instead of iterating from `curr` to `end`
+    pub planned: Vec<SliceItem>,
+    /// Time zone of the dates in `curr` and `end`
+    pub zone: Tz,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SliceItem {
     pub begin: String,
     pub end: String,
@@ -26,26 +40,49 @@ pub struct SliceItem {
 pub struct Requests {
     pub curr: usize,
     pub limit: usize,
+    /// Position of the first doc of a shard among the docs of all shards
+    pub offset: usize,
     pub num_docs: usize,
     pub url: String,
+    pub cursor: bool,
+    pub export: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Step {
     pub curr: usize,
     pub url: String,
+    pub paging: Paging,
+    /// Identity of the step kept across executions for resuming: the slice bounds and its first doc.
+    /// The pages of a cursorMark or /export chain share the key of the step starting the chain.
+    pub key: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Paging {
+    /// The page of docs is already defined by the `start` and `rows` in the url
+    Offset,
+    /// Follow the pages of docs until Solr returns the same `cursorMark` sent
+    Cursor { mark: String, limit: usize, num_docs: usize },
+    /// Stream all docs from the /export handler splitting them in pages of `num_docs`
+    Export { limit: usize, num_docs: usize },
 }
 
 #[derive(Debug)]
 pub struct Documents {
     pub step: Step,
     pub docs: String,
+    /// Number of documents in `docs`
+    pub count: usize,
+    /// Whether no more pages follow this one in the step (always true when paging by offset)
+    pub last_page: bool,
 }
 
 #[derive(Debug)]
 pub struct SolrCore {
     pub num_found: usize,
     pub fields: Vec<String>,
+    pub unique_key: Option<String>,
 }
 
 // endregion
@@ -57,33 +94,49 @@ impl Slices<String> {
         if self.curr.is_empty() {
             return Box::new(Self::get_slice_of(1, 1));
         }
+        if !self.planned.is_empty() {
+            return Box::new(self.planned.clone().into_iter());
+        }
         let res: Box<dyn Iterator<Item = SliceItem>> = match self.mode {
             IterateMode::None => Box::new(Self::get_slice_of(1, 1)),
             IterateMode::Range => Box::new(self.get_range_slices().unwrap()),
             _ => Box::new(self.get_period_slices().unwrap()),
         };
-        res
+        if self.skipping.is_empty() {
+            res
+        } else {
+            let skipping = self.skipping.clone();
+            Box::new(res.filter(move |item| !skipping.contains(&item.begin)))
+        }
     }
 
     pub fn estimate_steps(&self) -> BoxedResult<usize> {
         if self.curr.is_empty() {
             return Ok(1);
         }
+        if !self.planned.is_empty() {
+            return Ok(self.planned.len());
+        }
         let num: usize = match self.mode {
             IterateMode::None => 1,
             IterateMode::Range => self.get_range_slices()?.len(),
             _ => self.get_period_slices()?.len(),
         };
         let rem = num % self.increment;
-        if rem > 0 {
-            Ok(num + 1)
-        } else {
-            Ok(num)
-        }
+        let res = if rem > 0 { num + 1 } else { num };
+        Ok(res.saturating_sub(self.skipping.len()))
     }
 
     fn get_slice_of(num: usize, incr: usize) -> Slices<usize> {
-        Slices::<usize> { curr: 0, end: num, mode: IterateMode::Range, increment: incr }
+        Slices::<usize> {
+            curr: 0,
+            end: num,
+            mode: IterateMode::Range,
+            increment: incr,
+            skipping: Vec::new(),
+            planned: Vec::new(),
+            zone: Tz::UTC,
+        }
     }
 
     fn get_range_slices(&self) -> BoxedResult<Slices<usize>> {
@@ -94,17 +147,23 @@ impl Slices<String> {
             end: v2,
             increment: self.increment,
             mode: IterateMode::Range,
+            skipping: Vec::new(),
+            planned: Vec::new(),
+            zone: self.zone,
         })
     }
 
     fn get_period_slices(&self) -> BoxedResult<Slices<NaiveDateTime>> {
-        let v1 = Self::parse_between_date(self.curr.as_str())?;
-        let v2 = Self::parse_between_date(self.end.as_str())?;
+        let v1 = self.parse_between_date(self.curr.as_str())?;
+        let v2 = self.parse_between_date(self.end.as_str())?;
         Ok(Slices::<NaiveDateTime> {
             curr: v1,
             end: v2,
             increment: self.increment,
             mode: self.mode,
+            skipping: Vec::new(),
+            planned: Vec::new(),
+            zone: self.zone,
         })
     }
 
@@ -116,33 +175,33 @@ impl Slices<String> {
         }
     }
 
-    fn parse_between_date(value: &str) -> BoxedResult<NaiveDateTime> {
-        if value.contains('T') {
-            let time = value.parse::<NaiveDateTime>();
-            match time {
-                Err(_) => throw(format!("Wrong value for date: '{}'", value)),
-                Ok(quantity) => Ok(quantity),
-            }
-        } else {
-            let date = value.parse::<NaiveDate>();
-            match date {
-                Err(_) => throw(format!("Wrong value for date: '{}'", value)),
-                Ok(quantity) => Ok(quantity.and_hms(0, 0, 0)),
-            }
+    fn parse_between_date(&self, value: &str) -> BoxedResult<NaiveDateTime> {
+        let now = Utc::now().with_timezone(&self.zone).naive_local();
+        match parse_date_math(value, now) {
+            None => throw(format!("Wrong value for date: '{}'", value)),
+            Some(date) => Ok(date),
         }
     }
 }
 
 impl Slices<NaiveDateTime> {
-    fn get_interval(&self, less: i64) -> Duration {
+    /// Start of the slice following the one starting at `curr`.
+    /// The slices by week, month or year end on the boundaries of the calendar.
+    fn get_next(&self, curr: NaiveDateTime) -> NaiveDateTime {
         let plus = self.increment.to_i64();
-        let dur = match self.mode {
-            IterateMode::Minute => Duration::minutes(plus),
-            IterateMode::Hour => Duration::hours(plus),
-            IterateMode::Day => Duration::days(plus),
-            _ => Duration::days(365),
+        let next = match self.mode {
+            IterateMode::Minute => shift_date(curr, "MINUTE", plus),
+            IterateMode::Hour => shift_date(curr, "HOUR", plus),
+            IterateMode::Week => shift_date(round_to_period(curr, self.mode), "WEEK", plus),
+            IterateMode::Month => shift_date(round_to_period(curr, self.mode), "MONTH", plus),
+            IterateMode::Year => shift_date(round_to_period(curr, self.mode), "YEAR", plus),
+            _ => shift_date(curr, "DAY", plus),
         };
-        dur - Duration::seconds(less)
+        next.unwrap_or(self.end)
+    }
+
+    fn format_time(&self, local: NaiveDateTime) -> String {
+        format_solr_time(to_utc_time(self.zone, local))
     }
 
     fn len(&self) -> usize {
@@ -151,7 +210,15 @@ impl Slices<NaiveDateTime> {
             IterateMode::Minute => (dur.num_minutes(), dur.num_seconds(), 60i64),
             IterateMode::Hour => (dur.num_hours(), dur.num_minutes(), 60i64),
             IterateMode::Day => (dur.num_days(), dur.num_hours(), 24i64),
-            _ => (1i64, 1i64, 1i64),
+            _ => {
+                let mut count = 0;
+                let mut curr = self.curr;
+                while self.end > curr {
+                    count += 1;
+                    curr = self.get_next(curr);
+                }
+                return count;
+            }
         };
         if diff < 0 {
             0
@@ -199,11 +266,11 @@ impl Iterator for Slices<NaiveDateTime> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.end > self.curr {
-            let last = self.curr + self.get_interval(1);
+            let next = self.get_next(self.curr);
+            let last = next - Duration::seconds(1);
             let part = if last < self.end { last } else { self.end };
-            let res = SliceItem { begin: format_solr_time(self.curr), end: format_solr_time(part) };
-            let next = self.get_interval(0);
-            self.curr += next;
+            let res = SliceItem { begin: self.format_time(self.curr), end: self.format_time(part) };
+            self.curr = next;
             Some(res)
         } else {
             None
@@ -227,15 +294,57 @@ impl SliceItem {
         } else {
             let query =
                 replace_solr_vars(step.url.as_str(), self.begin.as_str(), self.end.as_str());
-            Step { url: query, curr: step.curr }
+            let key = format!("{}..{}@{}", self.begin, self.end, step.key);
+            Step { url: query, curr: step.curr, paging: step.paging, key }
+        }
+    }
+}
+
+impl Step {
+    /// Returns the url for querying the documents of this step.
+    /// When paging with cursorMark the `rows` and `cursorMark` are appended to the query.
+    pub fn get_query_url(&self) -> String {
+        match &self.paging {
+            Paging::Cursor { mark, limit, num_docs } => {
+                let rows = (*num_docs).min(limit - self.curr);
+                let encoded: String = byte_serialize(mark.as_bytes()).collect();
+                format!("{}&rows={}&cursorMark={}", self.url, rows, encoded)
+            }
+            _ => self.url.clone(),
+        }
+    }
+
+    /// Returns the step for the next page of the cursor or None when all docs were retrieved.
+    /// Solr signals the end of the documents returning the same cursorMark that was sent.
+    pub fn next_page(&self, next_mark: String) -> Option<Step> {
+        if let Paging::Cursor { mark, limit, num_docs } = &self.paging {
+            let next = self.curr + num_docs;
+            if next_mark == *mark || next >= *limit {
+                None
+            } else {
+                let paging = Paging::Cursor { mark: next_mark, limit: *limit, num_docs: *num_docs };
+                Some(Step { curr: next, url: self.url.clone(), paging, key: self.key.clone() })
+            }
+        } else {
+            None
         }
     }
+
+    /// Name of the json file holding the docs of the step: `docs_at_000000001.json`
+    pub fn get_docs_filename(&self) -> String {
+        format!("docs_at_{:09}.json", self.curr + 1)
+    }
+
+    /// Returns the step for storing the docs streamed from /export at the position `curr`
+    pub fn export_page(&self, curr: usize) -> Step {
+        Step { curr, url: self.url.clone(), paging: Paging::Offset, key: self.key.clone() }
+    }
 }
 
 impl Requests {
     pub fn len(&self) -> usize {
         let res = self.limit / self.num_docs;
-        if self.limit % self.num_docs == 0 {
+        if self.limit.is_multiple_of(self.num_docs) {
             res
         } else {
             res + 1
@@ -247,11 +356,25 @@ impl Iterator for Requests {
     type Item = Step;
 
     fn next(&mut self) -> Option<Step> {
-        if self.limit > self.curr {
+        if (self.cursor || self.export) && self.limit > self.curr {
+            // cursorMark and /export pages are sequential: the reader follows all of them
+            let (limit, num_docs) = (self.limit, self.num_docs);
+            let paging = if self.export {
+                Paging::Export { limit, num_docs }
+            } else {
+                Paging::Cursor { mark: "*".to_string(), limit, num_docs }
+            };
+            let key = self.curr.to_string();
+            let res = Step { url: self.url.clone(), curr: self.curr, paging, key };
+            self.curr = self.limit;
+            Some(res)
+        } else if self.limit > self.curr {
             let remaining = self.limit - self.curr;
             let rows = self.num_docs.min(remaining);
-            let query = format!("{}&start={}&rows={}", self.url, self.curr, rows);
-            let res = Step { url: query, curr: self.curr };
+            let start = self.curr - self.offset;
+            let query = format!("{}&start={}&rows={}", self.url, start, rows);
+            let key = self.curr.to_string();
+            let res = Step { url: query, curr: self.curr, paging: Paging::Offset, key };
             self.curr += self.num_docs;
             Some(res)
         } else {
@@ -270,56 +393,140 @@ impl Iterator for Requests {
     }
 }
 
+/// Alternates the steps of the requests of each shard for reading the shards in parallel
+pub fn interleave(requests: &[Requests]) -> impl Iterator<Item = Step> {
+    let mut pending = requests.to_vec();
+    let mut turn = 0;
+    std::iter::from_fn(move || {
+        while !pending.is_empty() {
+            let index = turn % pending.len();
+            match pending[index].next() {
+                Some(step) => {
+                    turn = index + 1;
+                    return Some(step);
+                }
+                None => {
+                    pending.remove(index);
+                    turn = index;
+                }
+            }
+        }
+        None
+    })
+}
+
 fn replace_solr_vars(query: &str, begin: &str, end: &str) -> String {
     let query2 = replace_solr_date(query, "{begin}", begin);
     replace_solr_date(&query2, "{end}", end)
 }
 
-fn format_solr_time(date_time: NaiveDateTime) -> String {
+pub fn format_solr_time(date_time: NaiveDateTime) -> String {
     date_time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
 }
 
+/// Converts the wall clock time in the `zone` to UTC. Times skipped by daylight saving are moved forward.
+pub fn to_utc_time(zone: Tz, local: NaiveDateTime) -> NaiveDateTime {
+    match zone.from_local_datetime(&local) {
+        LocalResult::Single(date) | LocalResult::Ambiguous(date, _) => date.naive_utc(),
+        LocalResult::None => to_utc_time(zone, local + Duration::hours(1)),
+    }
+}
+
+const SELECT_HANDLER: &str = "/select?wt=json&indent=off&omitHeader=true";
+const EXPORT_HANDLER: &str = "/export?wt=json";
+
 // endregion
 
 // region Solr requests
 
 impl Backup {
-    pub fn get_archive_pattern(&self, num_found: usize) -> String {
-        let prefix = match &self.zip_prefix {
-            Some(text) => text.to_string(),
-            None => {
-                let now: DateTime<Utc> = Utc::now();
-                let time = now.format("%Y%m%d_%H%M");
-                format!("{}_at_{}", &self.options.core, &time)
-            }
+    /// Returns the prefix for naming the archives and the journal of the backup.
+    /// When resuming without `--zip-prefix` the prefix of the newest journal of the core is used.
+    pub fn get_archive_prefix(&self) -> BoxedResult<String> {
+        let name = match &self.label {
+            Some(label) => format!("{}_{}", self.options.core, label),
+            None => self.options.core.clone(),
         };
+        if let Some(text) = &self.zip_prefix {
+            return match &self.label {
+                Some(label) => Ok(format!("{}_{}", text, label)),
+                None => Ok(text.to_string()),
+            };
+        }
+        if self.resume {
+            let pattern = self.dir.join(format!("{}_at_*.journal", name));
+            let wilcard = pattern.to_str().unwrap();
+            let mut found = glob(wilcard)?.filter_map(Result::ok).collect::<Vec<_>>();
+            found.sort_unstable();
+            return match found.last().and_then(|newest| newest.file_stem()) {
+                Some(stem) => Ok(stem.to_string_lossy().to_string()),
+                None => throw(format!("Found no journal for resuming the backup: {}", wilcard)),
+            };
+        }
+        let now: DateTime<Utc> = Utc::now();
+        let time = now.format("%Y%m%d_%H%M");
+        Ok(format!("{}_at_{}", name, &time))
+    }
+
+    pub fn get_archive_pattern(&self, prefix: &str, num_found: usize) -> String {
         format!("{}_docs_{}_seq_{}.zip", prefix, num_found, BRACKETS)
     }
 
+    pub fn get_journal_path(&self, prefix: &str) -> PathBuf {
+        self.dir.join(format!("{}.journal", prefix))
+    }
+
+    pub fn querying(&self) -> Querying<'_> {
+        Querying { source: &self.source, options: &self.options, transfer: &self.transfer }
+    }
+}
+
+/// The source core, query and transfer settings for retrieving the docs in `backup` and `copy`
+pub struct Querying<'a> {
+    pub source: &'a QueryArgs,
+    pub options: &'a CommonArgs,
+    pub transfer: &'a ParallelArgs,
+}
+
+impl Querying<'_> {
     pub fn estimate_docs_quantity(
-        &self, schema: &SolrCore, slices: &Slices<String>,
+        &self, requests: &[Requests], slices: &Slices<String>,
     ) -> BoxedResult<usize> {
-        let end_limit = self.get_docs_to_retrieve(schema);
-        let num_retrieving = end_limit - self.skip;
+        let num_retrieving: usize = requests.iter().map(|req| req.limit - req.curr).sum();
 
         let slice_count = slices.estimate_steps()?;
         Ok(num_retrieving * slice_count)
     }
 
     pub fn get_docs_to_retrieve(&self, schema: &SolrCore) -> usize {
-        schema.num_found.min(self.limit.unwrap_or(std::usize::MAX))
+        schema.num_found.min(self.source.limit.unwrap_or(usize::MAX))
     }
 
     pub fn get_steps(&self, schema: &SolrCore) -> Requests {
         let core_fields: &[String] = &schema.fields;
         let fl = self.get_query_fields(core_fields);
-        let query = self.get_query_url(&fl, true);
+        let unique_key = schema.unique_key.as_deref().unwrap_or(EMPTY_STR);
+        let handler = if self.source.export { EXPORT_HANDLER } else { SELECT_HANDLER };
+        let query = self.get_handler_url(handler, &fl, true, unique_key);
         let end_limit = self.get_docs_to_retrieve(schema);
-        Requests { curr: self.skip, limit: end_limit, num_docs: self.num_docs, url: query }
+        Requests {
+            curr: self.source.skip,
+            limit: end_limit,
+            offset: 0,
+            num_docs: self.source.num_docs,
+            url: query,
+            cursor: self.source.cursor,
+            export: self.source.export,
+        }
     }
 
+    /// The `fl` param with the fields of `--select` or the ones picked by `inspect_core` with globs
     pub fn get_query_fields(&self, core_fields: &[String]) -> String {
-        let fields = if self.select.is_empty() { core_fields } else { &self.select };
+        let fields = if self.source.select.is_empty() || self.needs_field_list() {
+            core_fields
+        } else {
+            &self.source.select
+        };
         if fields.is_empty() {
             EMPTY_STRING
         } else {
@@ -334,7 +541,7 @@ impl Backup {
     }
 
     pub fn replace_vars(&self, query: &str, raw: bool) -> String {
-        if raw || self.iterate_between.is_empty() {
+        if raw || self.source.iterate_between.is_empty() {
             query.to_string()
         } else {
             let (begin, end) = self.get_between();
@@ -343,50 +550,178 @@ impl Backup {
     }
 
     pub fn get_query_url(&self, selected: &str, raw: bool) -> String {
-        let qparam = self.query.as_deref().unwrap_or("*:*");
+        self.get_handler_url(SELECT_HANDLER, selected, raw, EMPTY_STR)
+    }
+
+    /// Same as `get_query_url` but querying the `handler` and appending the `unique_key`
+    /// as tiebreaker in the sort order
+    pub fn get_handler_url(
+        &self, handler: &str, selected: &str, raw: bool, unique_key: &str,
+    ) -> String {
+        let qparam = self.source.query.as_deref().unwrap_or("*:*");
         let qfixed = self.replace_vars(qparam, raw);
         let filter = solr_query(&qfixed);
-
-        let sort: String = if self.order.is_empty() {
+        let filters: String = self
+            .source
+            .filter
+            .iter()
+            .map(|fq| format!("&fq={}", solr_query(&self.replace_vars(fq, raw))))
+            .collect();
+
+        let mut all: Vec<String> =
+            self.source.order.iter().map(|field| field.to_string()).collect();
+        if !unique_key.is_empty() && !self.source.order.iter().any(|sf| sf.field == unique_key) {
+            all.push(format!("{}%20asc", unique_key));
+        }
+        let sort: String = if all.is_empty() {
             EMPTY_STRING
         } else {
-            let all: Vec<String> = self.order.iter().map(|field| field.to_string()).collect();
             let joined = all.join(COMMA);
             "&sort=".append(&joined)
         };
-        let parts = vec![
+        let parts = [
             self.options.url.with_suffix("/"),
             self.options.core.clone(),
-            "/select?wt=json&indent=off&omitHeader=true".to_string(),
+            handler.to_string(),
             format!("&q={}", filter),
+            filters,
             sort,
             self.transfer.get_param("&"),
+            self.source.time_zone.as_ref().map_or(EMPTY_STRING, |zone| format!("&TZ={}", zone)),
             selected.to_string(),
         ];
         parts.concat()
     }
 
-    pub fn get_slices(&self) -> Slices<String> {
+    /// Url for querying the docs of all slices with `params` appended
+    pub fn get_unsliced_url(&self, params: &str) -> String {
+        let url = self.get_handler_url(SELECT_HANDLER, params, true, EMPTY_STR);
+        url.replace("{begin}", "*").replace("{end}", "*")
+    }
+
+    /// Url for counting the docs of the query in the `slice`
+    pub fn get_slice_count_url(&self, slice: &SliceItem) -> String {
+        let url = self.get_handler_url(SELECT_HANDLER, "&rows=0", true, EMPTY_STR);
+        replace_solr_vars(&url, &slice.begin, &slice.end)
+    }
+
+    pub fn get_slices(&self) -> BoxedResult<Slices<String>> {
         let (begin, end) = self.get_between();
-        Slices::<String> {
+        Ok(Slices::<String> {
             curr: begin.to_string(),
             end: end.to_string(),
-            increment: self.iterate_step,
-            mode: self.iterate_by,
+            increment: self.source.iterate_step,
+            mode: self.source.iterate_by,
+            skipping: Vec::new(),
+            planned: Vec::new(),
+            zone: self.get_time_zone()?,
+        })
+    }
+
+    /// Time zone in `--time-zone` or UTC
+    pub fn get_time_zone(&self) -> BoxedResult<Tz> {
+        match &self.source.time_zone {
+            None => Ok(Tz::UTC),
+            Some(name) => {
+                name.parse::<Tz>().or_else(|_| throw(format!("Unknown time zone: '{}'", name)))
+            }
         }
     }
 
     fn get_between(&self) -> (&str, &str) {
-        if self.iterate_between.is_empty() {
+        if self.source.iterate_between.is_empty() {
             (EMPTY_STR, EMPTY_STR)
         } else {
-            (self.iterate_between[0].as_str(), self.iterate_between[1].as_str())
+            (self.source.iterate_between[0].as_str(), self.source.iterate_between[1].as_str())
         }
     }
 }
 
 // endregion
 
+// region Date math
+
+/// Evaluates dates like `2020-04-01`, `2020-04-01T10:00:00` or Solr date math like `NOW/DAY-7DAYS`
+fn parse_date_math(value: &str, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let (mut res, mut ops) = match value.strip_prefix("NOW") {
+        Some(rest) => (now, rest),
+        None => {
+            let (date, rest) = value.split_at(value.find('Z').unwrap_or(value.len()));
+            let date = if date.contains('T') {
+                date.parse::<NaiveDateTime>().ok()?
+            } else {
+                date.parse::<NaiveDate>().ok()?.and_hms_opt(0, 0, 0)?
+            };
+            (date, rest.strip_prefix('Z').unwrap_or(rest))
+        }
+    };
+    while let Some(op) = ops.chars().next() {
+        let tail = &ops[1..];
+        let digits = tail.chars().take_while(char::is_ascii_digit).count();
+        let letters = tail[digits..].chars().take_while(char::is_ascii_alphabetic).count();
+        let unit = &tail[digits..digits + letters];
+        res = match op {
+            '/' if digits == 0 => round_date(res, unit)?,
+            '+' | '-' if digits > 0 => {
+                let amount = tail[..digits].parse::<i64>().ok()?;
+                shift_date(res, unit, if op == '-' { -amount } else { amount })?
+            }
+            _ => return None,
+        };
+        ops = &tail[digits + letters..];
+    }
+    Some(res)
+}
+
+/// Rounds down the date to the start of the `unit` like Solr date math: `/DAY`
+fn round_date(date: NaiveDateTime, unit: &str) -> Option<NaiveDateTime> {
+    let day = date.date();
+    let start = match unit.trim_end_matches('S') {
+        "YEAR" => NaiveDate::from_ymd_opt(day.year(), 1, 1)?,
+        "MONTH" => NaiveDate::from_ymd_opt(day.year(), day.month(), 1)?,
+        "WEEK" => day - Duration::days(day.weekday().num_days_from_monday().into()),
+        "DAY" | "DATE" => day,
+        "HOUR" => return day.and_hms_opt(date.hour(), 0, 0),
+        "MINUTE" => return day.and_hms_opt(date.hour(), date.minute(), 0),
+        "SECOND" => return day.and_hms_opt(date.hour(), date.minute(), date.second()),
+        _ => return None,
+    };
+    start.and_hms_opt(0, 0, 0)
+}
+
+/// Adds the `amount` of `unit` to the date like Solr date math: `+7DAYS`
+fn shift_date(date: NaiveDateTime, unit: &str, amount: i64) -> Option<NaiveDateTime> {
+    let months = match unit.trim_end_matches('S') {
+        "YEAR" => amount.checked_mul(12)?,
+        "MONTH" => amount,
+        "WEEK" => return date.checked_add_signed(Duration::try_weeks(amount)?),
+        "DAY" | "DATE" => return date.checked_add_signed(Duration::try_days(amount)?),
+        "HOUR" => return date.checked_add_signed(Duration::try_hours(amount)?),
+        "MINUTE" => return date.checked_add_signed(Duration::try_minutes(amount)?),
+        "SECOND" => return date.checked_add_signed(Duration::try_seconds(amount)?),
+        _ => return None,
+    };
+    let count = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+    if months < 0 {
+        date.checked_sub_months(count)
+    } else {
+        date.checked_add_months(count)
+    }
+}
+
+/// Start of the week, month or year of the date. Other modes keep the date.
+pub fn round_to_period(date: NaiveDateTime, mode: IterateMode) -> NaiveDateTime {
+    let unit = match mode {
+        IterateMode::Week => "WEEK",
+        IterateMode::Month => "MONTH",
+        IterateMode::Year => "YEAR",
+        _ => return date,
+    };
+    round_date(date, unit).unwrap_or(date)
+}
+
+// endregion
+
 #[cfg(test)]
 mod tests {
     // region mockup
@@ -401,7 +736,7 @@ mod tests {
     impl Arguments {
         pub fn get(&self) -> BoxedResult<&Backup> {
             match &self {
-                Self::Backup(gets) => Ok(&gets),
+                Self::Backup(gets) => Ok(gets),
                 _ => raise("command must be 'backup' !"),
             }
         }
@@ -409,7 +744,11 @@ mod tests {
 
     impl SolrCore {
         pub fn mockup() -> Self {
-            SolrCore { num_found: 100, fields: vec![TEST_SELECT_FIELDS.split(COMMA).collect()] }
+            SolrCore {
+                num_found: 100,
+                fields: vec![TEST_SELECT_FIELDS.split(COMMA).collect()],
+                unique_key: None,
+            }
         }
     }
 
@@ -422,24 +761,113 @@ mod tests {
         let parsed = Arguments::mockup_args_backup();
         let gets = parsed.get().unwrap();
         let core_info = SolrCore::mockup();
-        let query = gets.get_query_url(EMPTY_STR, true);
+        let query = gets.querying().get_query_url(EMPTY_STR, true);
 
         let mut i = 0;
-        for step in gets.get_steps(&core_info) {
+        for step in gets.querying().get_steps(&core_info) {
             let url = step.url;
-            assert_eq!(url.is_empty(), false);
-            assert_eq!(url.starts_with(&query), true);
+            assert!(!url.is_empty());
+            assert!(url.starts_with(&query));
             i += 1;
         }
         assert_eq!(i, 8);
     }
 
+    #[test]
+    fn check_query_fields_of_select() {
+        let parsed = Arguments::mockup_args_backup();
+        let gets = parsed.get().unwrap();
+        assert!(!gets.querying().needs_field_list());
+        assert_eq!(gets.querying().get_query_fields(&[]), format!("&fl={}", TEST_SELECT_FIELDS));
+
+        let parsed = Arguments::mockup_args_backup_with(&["--exclude", "date"]);
+        let gets = parsed.get().unwrap();
+        assert!(gets.querying().needs_field_list());
+        let picked = vec!["id".to_string(), "vehiclePlate".to_string()];
+        assert_eq!(gets.querying().get_query_fields(&picked), "&fl=id,vehiclePlate");
+    }
+
+    #[test]
+    fn check_query_with_filters() {
+        let parsed = Arguments::mockup_args_backup();
+        let mut gets = parsed.get().unwrap().clone();
+        gets.source.filter = vec!["type:car".to_string(), "date:[{begin} TO {end}]".to_string()];
+        let url = gets.querying().get_query_url(EMPTY_STR, true);
+        assert!(url.contains("&fq=type:car&fq=date:[{begin}%20TO%20{end}]"));
+
+        gets.zip_prefix = None;
+        gets.label = Some("tenant1".to_string());
+        assert!(gets.get_archive_prefix().unwrap().starts_with("mileage_tenant1_at_"));
+    }
+
+    #[test]
+    fn check_iterator_for_cursor_mark() {
+        let parsed = Arguments::mockup_args_backup();
+        let gets = parsed.get().unwrap();
+        let mut core_info = SolrCore::mockup();
+        core_info.unique_key = Some("id".to_string());
+
+        let mut requests = gets.querying().get_steps(&core_info);
+        requests.cursor = true;
+
+        let steps: Vec<Step> = requests.collect();
+        assert_eq!(steps.len(), 1);
+
+        let first = &steps[0];
+        let url = first.get_query_url();
+        assert!(url.ends_with("&rows=5&cursorMark=*"));
+        assert!(url.contains("&sort=date%20asc,id%20desc,vehiclePlate%20asc&"));
+
+        let second = first.next_page("AoE/b+c=".to_string()).unwrap();
+        assert!(matches!(second.paging, Paging::Cursor { .. }));
+        assert_eq!(second.curr, first.curr + 5);
+        assert!(second.get_query_url().ends_with("&cursorMark=AoE%2Fb%2Bc%3D"));
+        assert!(second.next_page("AoE/b+c=".to_string()).is_none());
+    }
+
+    #[test]
+    fn check_iterator_for_shards() {
+        let shard = |offset: usize, count: usize, url: &str| Requests {
+            curr: offset,
+            limit: offset + count,
+            offset,
+            num_docs: 5,
+            url: url.to_string(),
+            cursor: false,
+            export: false,
+        };
+        let shards = [shard(0, 15, "a"), shard(15, 5, "b")];
+        let steps: Vec<Step> = interleave(&shards).collect();
+        let urls: Vec<&str> = steps.iter().map(|step| step.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec!["a&start=0&rows=5", "b&start=0&rows=5", "a&start=5&rows=5", "a&start=10&rows=5"]
+        );
+        assert_eq!(steps[1].get_docs_filename(), "docs_at_000000016.json");
+    }
+
+    #[test]
+    fn check_iterator_for_export() {
+        let parsed = Arguments::mockup_args_backup_with(&["--export"]);
+        let gets = parsed.get().unwrap();
+        let core_info = SolrCore::mockup();
+
+        let steps: Vec<Step> = gets.querying().get_steps(&core_info).collect();
+        assert_eq!(steps.len(), 1);
+
+        let first = &steps[0];
+        assert!(first.get_query_url().contains("/export?wt=json&q="));
+        assert!(matches!(first.paging, Paging::Export { limit: 42, num_docs: 5 }));
+        assert_eq!(first.export_page(8).key, "3");
+        assert!(first.next_page("*".to_string()).is_none());
+    }
+
     #[test]
     fn check_iterator_for_slices_usize() {
         let slices = Slices::<String>::get_slice_of(16, 2);
         for step in slices {
             // print!("# {} -> {}", step.begin, step.end);
-            assert_eq!(step.begin < step.end, true)
+            assert!(step.begin < step.end)
         }
     }
 
@@ -450,18 +878,59 @@ mod tests {
             end: "2020-04-03T11:12:13".to_string(),
             increment: 1,
             mode: IterateMode::Day,
+            skipping: Vec::new(),
+            planned: Vec::new(),
+            zone: Tz::UTC,
         };
 
         let slices = src.get_period_slices();
-        assert!(slices.is_ok(), true);
+        assert!(slices.is_ok());
 
         if let Ok(seq) = slices {
             for step in seq {
                 // print!("# {} -> {}", step.begin, step.end);
-                assert_eq!(step.begin < step.end, true)
+                assert!(step.begin < step.end)
             }
         }
     }
 
+    #[test]
+    fn check_iterator_for_slices_by_month() {
+        let src = Slices::<String> {
+            curr: "2020-01-15".to_string(),
+            end: "2020-04-01".to_string(),
+            increment: 1,
+            mode: IterateMode::Month,
+            skipping: Vec::new(),
+            planned: Vec::new(),
+            zone: "America/Sao_Paulo".parse().unwrap(),
+        };
+        assert_eq!(src.estimate_steps().unwrap(), 3);
+
+        let slices: Vec<SliceItem> = src.get_iterator().collect();
+        assert_eq!(slices.len(), 3);
+        assert_eq!(slices[0].begin, "2020-01-15T03:00:00Z");
+        assert_eq!(slices[0].end, "2020-02-01T02:59:59Z");
+        assert_eq!(slices[2].begin, "2020-03-01T03:00:00Z");
+        assert_eq!(slices[2].end, "2020-04-01T02:59:59Z");
+    }
+
+    #[test]
+    fn check_parse_date_math() {
+        let now: NaiveDateTime = "2020-04-15T10:20:30".parse().unwrap();
+        let date = |value: &str| parse_date_math(value, now).map(format_solr_time);
+
+        assert_eq!(date("2020-04-01").unwrap(), "2020-04-01T00:00:00Z");
+        assert_eq!(date("2020-04-01T11:12:13Z").unwrap(), "2020-04-01T11:12:13Z");
+        assert_eq!(date("NOW").unwrap(), "2020-04-15T10:20:30Z");
+        assert_eq!(date("NOW/DAY-7DAYS").unwrap(), "2020-04-08T00:00:00Z");
+        assert_eq!(date("NOW/MONTH+1MONTH-1SECOND").unwrap(), "2020-04-30T23:59:59Z");
+        assert_eq!(date("NOW/WEEK").unwrap(), "2020-04-13T00:00:00Z");
+        assert_eq!(date("2020-01-31T00:00:00Z+1MONTH/YEAR").unwrap(), "2020-01-01T00:00:00Z");
+        assert!(date("NOW/FORTNIGHT").is_none());
+        assert!(date("NOW+DAY").is_none());
+        assert!(date("yesterday").is_none());
+    }
+
     // endregion
 }
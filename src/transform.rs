@@ -0,0 +1,211 @@
+use log::debug;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use std::{fs::File, io::BufReader, path::Path};
+
+use crate::{args::TransformArgs, fails::*};
+
+// region Transformer
+
+pub type Document = Map<String, Value>;
+
+const VALUE_VAR: &str = "{value}";
+
+/// Changes the fields of the documents being restored
+#[derive(Debug, Default)]
+pub struct Transformer {
+    drop: Vec<String>,
+    rename: Vec<(String, String)>,
+    map: Vec<(String, String)>,
+    set: Vec<(String, Value)>,
+}
+
+/// The transformations read from the file in `--transform-file`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct TransformFile {
+    drop: Vec<String>,
+    /// Kept in the order of the file as `preserve_order` is enabled, for chaining renames like `a->b, b->c`
+    rename: Map<String, Value>,
+    map: Map<String, Value>,
+    set: Map<String, Value>,
+}
+
+impl Transformer {
+    /// Collects the transformations of the file and the command line. None when there are none.
+    pub fn from_args(args: &TransformArgs) -> BoxedResult<Option<Self>> {
+        let mut res = Transformer::default();
+        if let Some(path) = &args.transform_file {
+            let file = File::open(path).or_else(|cause| {
+                throw(format!("Cannot read transformations in {:?}: {}", path, cause))
+            })?;
+            let parsed: TransformFile =
+                serde_json::from_reader(BufReader::new(file)).or_else(|cause| {
+                    throw(format!("Invalid transformations in {:?}: {}", path, cause))
+                })?;
+            res.drop.extend(parsed.drop);
+            res.rename.extend(to_text_pairs(parsed.rename, "rename", path)?);
+            res.map.extend(to_text_pairs(parsed.map, "map", path)?);
+            res.set.extend(parsed.set);
+        }
+        res.drop.extend(args.drop_fields.iter().cloned());
+        res.rename.extend(args.rename.iter().cloned());
+        res.map.extend(args.map_fields.iter().cloned());
+        for (field, value) in &args.set_fields {
+            let parsed =
+                serde_json::from_str(value).unwrap_or_else(|_| Value::from(value.as_str()));
+            res.set.push((field.clone(), parsed));
+        }
+        if res.is_empty() {
+            Ok(None)
+        } else {
+            debug!("Transforming documents with: {:?}", res);
+            Ok(Some(res))
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.drop.is_empty() && self.rename.is_empty() && self.map.is_empty() && self.set.is_empty()
+    }
+
+    /// Applies the transformations to every document in the json array
+    pub fn apply(&self, json: &str) -> Result<String, serde_json::Error> {
+        let mut docs: Vec<Document> = serde_json::from_str(json)?;
+        for doc in docs.iter_mut() {
+            self.apply_to(doc);
+        }
+        serde_json::to_string(&docs)
+    }
+
+    pub fn apply_to(&self, doc: &mut Document) {
+        for field in &self.drop {
+            doc.remove(field);
+        }
+        for (old, new) in &self.rename {
+            if let Some(value) = doc.remove(old) {
+                doc.insert(new.clone(), value);
+            }
+        }
+        for (field, template) in &self.map {
+            if let Some(value) = doc.get_mut(field) {
+                map_value(value, template);
+            }
+        }
+        for (field, value) in &self.set {
+            doc.insert(field.clone(), value.clone());
+        }
+    }
+}
+
+fn to_text_pairs(
+    section: Map<String, Value>, name: &str, path: &Path,
+) -> BoxedResult<Vec<(String, String)>> {
+    let mut res = Vec::with_capacity(section.len());
+    for (field, value) in section {
+        match value {
+            Value::String(text) => res.push((field, text)),
+            _ => throw(format!("Invalid {} of {} in {:?}: {}", name, field, path, value))?,
+        }
+    }
+    Ok(res)
+}
+
+/// Replaces the value with the template filled with it. Multivalued fields have each value replaced.
+fn map_value(value: &mut Value, template: &str) {
+    let text = match value {
+        Value::Array(values) => {
+            values.iter_mut().for_each(|item| map_value(item, template));
+            return;
+        }
+        Value::String(text) => text.clone(),
+        Value::Number(num) => num.to_string(),
+        Value::Bool(flag) => flag.to_string(),
+        Value::Null | Value::Object(_) => return,
+    };
+    *value = Value::String(template.replace(VALUE_VAR, &text));
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::{args::*, transform::*};
+    use structopt::StructOpt;
+
+    const DOCS: &str = r#"[{"id":"1","old":"a","tmp":1,"tags":["x","y"]},{"id":2,"tmp":2}]"#;
+
+    fn transformer_of(args: &[&str]) -> Option<Transformer> {
+        let mut all = vec![
+            "solrcopy",
+            "restore",
+            "--url",
+            "http://localhost:8983/solr",
+            "--core",
+            "c",
+            "-d",
+            ".",
+        ];
+        all.extend(args);
+        match Arguments::from_iter(all) {
+            Arguments::Restore(put) => Transformer::from_args(&put.transform).unwrap(),
+            _ => panic!("command must be 'restore' !"),
+        }
+    }
+
+    #[test]
+    fn check_transform_docs() {
+        let transformer = transformer_of(&[
+            "--drop",
+            "tmp",
+            "--rename",
+            "old=new",
+            "--map",
+            "id={value}_v2",
+            "tags=t_{value}",
+            "--set",
+            "active=true",
+            "origin=legacy",
+        ])
+        .unwrap();
+
+        let json = transformer.apply(DOCS).unwrap();
+        let docs: Vec<Document> = serde_json::from_str(&json).unwrap();
+        assert_eq!(docs[0]["id"], "1_v2");
+        assert_eq!(docs[0]["new"], "a");
+        assert_eq!(docs[0]["tags"], serde_json::json!(["t_x", "t_y"]));
+        assert_eq!(docs[0]["active"], true);
+        assert_eq!(docs[1]["id"], "2_v2");
+        assert_eq!(docs[1]["origin"], "legacy");
+        assert!(!docs[0].contains_key("old") && !docs[1].contains_key("tmp"));
+    }
+
+    #[test]
+    fn check_transform_file_order() {
+        let path = std::env::temp_dir().join("solrcopy_check_transform_file_order.json");
+        std::fs::write(&path, r#"{"rename":{"old":"tmp","tmp":"new","id":"key"}}"#).unwrap();
+        let transformer = transformer_of(&["--transform-file", path.to_str().unwrap()]).unwrap();
+        let json = transformer.apply(DOCS).unwrap();
+        let docs: Vec<Document> = serde_json::from_str(&json).unwrap();
+        assert_eq!(docs[0]["new"], "a");
+        assert_eq!(docs[1]["new"], 2);
+        assert!(!docs[0].contains_key("old") && !docs[0].contains_key("tmp"));
+
+        std::fs::write(&path, r#"{"map":{"id":1}}"#).unwrap();
+        let args = TransformArgs {
+            rename: vec![],
+            drop_fields: vec![],
+            set_fields: vec![],
+            map_fields: vec![],
+            transform_file: Some(path.clone()),
+        };
+        assert!(Transformer::from_args(&args).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn check_transform_none() {
+        assert!(transformer_of(&[]).is_none());
+        assert!(transformer_of(&["--drop", "tmp"]).unwrap().apply("{}").is_err());
+    }
+}
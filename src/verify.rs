@@ -0,0 +1,255 @@
+use glob::glob;
+use log::{debug, error, info};
+use serde_json::value::RawValue;
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    args::Verify,
+    fails::*,
+    ingest::ArchiveReader,
+    manifest::{file_sha256, ArchiveSummary, Manifest, MANIFEST_SUFFIX},
+};
+
+pub(crate) fn verify_main(params: Verify) -> BoxedError {
+    debug!("# VERIFY {:?}", params);
+
+    let found = params.find_manifests()?;
+    if found.is_empty() {
+        throw(format!("Found no backup manifests matching: {}", params.get_pattern()))?;
+    }
+
+    let mut checked = 0;
+    let mut failed = 0;
+    for manifest_path in found {
+        let manifest = Manifest::load(&manifest_path)?;
+        info!("Verifying backup of core {} in {:?}", manifest.core, manifest_path);
+
+        for summary in manifest.archives.into_inner().unwrap() {
+            let problems = verify_archive(&params.dir, &summary);
+            if problems.is_empty() {
+                debug!("  Archive {} is ok", summary.name);
+            } else {
+                error!(
+                    "  Archive {} failed verification:\n  {}",
+                    summary.name,
+                    problems.join("\n  ")
+                );
+                failed += 1;
+            }
+            checked += 1;
+        }
+    }
+    if failed > 0 {
+        throw(format!("{} of {} archives failed verification.", failed, checked))
+    } else {
+        info!("Verified {} archives.", checked);
+        Ok(())
+    }
+}
+
+impl Verify {
+    pub fn get_pattern(&self) -> String {
+        let wilcard = match &self.search {
+            Some(pat) if pat.ends_with(MANIFEST_SUFFIX) => pat.to_owned(),
+            Some(pat) => format!("{}*{}", pat.trim_end_matches('*'), MANIFEST_SUFFIX),
+            None => format!("*{}", MANIFEST_SUFFIX),
+        };
+        self.dir.join(wilcard).to_str().unwrap().to_string()
+    }
+
+    pub fn find_manifests(&self) -> BoxedResult<Vec<PathBuf>> {
+        let listed = glob(&self.get_pattern())?;
+        let mut found = listed.filter_map(Result::ok).collect::<Vec<_>>();
+        found.sort_unstable();
+        Ok(found)
+    }
+}
+
+/// Checks the archive against its summary in the manifest returning the mismatches found
+fn verify_archive(folder: &Path, summary: &ArchiveSummary) -> Vec<String> {
+    let archive_path = folder.join(&summary.name);
+    let mut problems = Vec::new();
+
+    match file_sha256(&archive_path) {
+        Err(cause) => return vec![format!("cannot read archive: {}", cause)],
+        Ok(sha256) if sha256 != summary.sha256 => {
+            problems.push(format!("sha256 {} should be {}", sha256, summary.sha256))
+        }
+        Ok(_) => {}
+    }
+    let mut archive_reader = match ArchiveReader::create_reader(&archive_path) {
+        Err(cause) => {
+            problems.push(format!("cannot open zip: {}", cause));
+            return problems;
+        }
+        Ok(reader) => reader,
+    };
+
+    let mut read = 0;
+    for index in 0..archive_reader.archive.len() {
+        let (entry_name, entry_contents) = match archive_reader.read_entry(index) {
+            Err(cause) => {
+                problems.push(format!("cannot read entry #{}: {}", index + 1, cause));
+                continue;
+            }
+            Ok(entry) => entry,
+        };
+        let expected = summary.entries.iter().find(|entry| entry.name == entry_name);
+        let parsed: Result<Vec<&RawValue>, _> = serde_json::from_str(&entry_contents);
+        match (parsed, expected) {
+            (Err(cause), _) => {
+                problems.push(format!("{} is not a json array: {}", entry_name, cause))
+            }
+            (Ok(_), None) => problems.push(format!("{} is missing in the manifest", entry_name)),
+            (Ok(docs), Some(entry)) if docs.len() != entry.docs => problems.push(format!(
+                "{} has {} docs but should have {}",
+                entry_name,
+                docs.len(),
+                entry.docs
+            )),
+            (Ok(_), Some(_)) => {}
+        }
+        read += 1;
+    }
+    if read != summary.entries.len() {
+        problems.push(format!("read {} entries but should have {}", read, summary.entries.len()));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        args::ArchiveFormat,
+        ingest::ArchiveReader,
+        journal::Journal,
+        manifest::*,
+        save::Archiver,
+        steps::{Documents, Paging, Step},
+        verify::*,
+    };
+    use std::{fs, sync::Arc, sync::Mutex};
+
+    fn docs(curr: usize, json: &str, count: usize) -> Documents {
+        let key = curr.to_string();
+        let step = Step { curr, url: "q".to_string(), paging: Paging::Offset, key };
+        Documents { step, docs: json.to_string(), count, last_page: true }
+    }
+
+    fn manifest() -> Manifest {
+        Manifest {
+            version: String::new(),
+            url: String::new(),
+            core: "demo".to_string(),
+            query: None,
+            label: None,
+            filter: vec![],
+            order: vec![],
+            select: vec![],
+            exclude: vec![],
+            skip: 0,
+            limit: None,
+            paging: "offset".to_string(),
+            iterate_by: String::new(),
+            iterate_field: None,
+            between: vec![],
+            step: 1,
+            time_zone: None,
+            num_found: 3,
+            started: String::new(),
+            finished: None,
+            archives: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn check_verify_archive() {
+        let dir = std::env::temp_dir().join("solrcopy_check_verify_archive");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+
+        let manifest = manifest();
+        let journal = Arc::new(Journal::open(&dir.join("demo.journal"), false).unwrap());
+        let mut archiver = Archiver::write_on(&dir, "demo_{}.zip", 10, journal, &manifest);
+        archiver.write_documents(&docs(0, r#"[{"id":1},{"id":2}]"#, 2)).unwrap();
+        archiver.write_documents(&docs(2, r#"[{"id":3}]"#, 1)).unwrap();
+        drop(archiver);
+
+        let summary = manifest.archives.lock().unwrap()[0].clone();
+        assert_eq!(summary.docs, 3);
+        assert!(verify_archive(&dir, &summary).is_empty());
+
+        let mut wrong = summary.clone();
+        wrong.entries[1].docs = 5;
+        assert_eq!(verify_archive(&dir, &wrong).len(), 1);
+
+        let zip_file = dir.join(&summary.name);
+        let contents = fs::read(&zip_file).unwrap();
+        let mut header = contents.clone();
+        header[0] = b'X';
+        fs::write(&zip_file, &header).unwrap();
+        let problems = verify_archive(&dir, &summary);
+        assert!(problems.iter().any(|problem| problem.starts_with("cannot read entry #1")));
+
+        fs::write(&zip_file, &contents[..contents.len() / 2]).unwrap();
+        assert!(!verify_archive(&dir, &summary).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_jsonl_archive() {
+        let dir = std::env::temp_dir().join("solrcopy_check_jsonl_archive");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+
+        let manifest = manifest();
+        let journal = Arc::new(Journal::open(&dir.join("demo.journal"), false).unwrap());
+        let mut archiver = Archiver::write_on(&dir, "demo_{}.zip", 10, journal, &manifest)
+            .with_format(ArchiveFormat::Jsonl);
+        archiver
+            .write_documents(&docs(0, "[{\"id\":1},\n {\"id\":2,\n \"n\":\"a\\nb\"}]", 2))
+            .unwrap();
+        archiver.write_documents(&docs(2, r#"[{"id":3}]"#, 1)).unwrap();
+        drop(archiver);
+
+        let summary = manifest.archives.lock().unwrap()[0].clone();
+        assert_eq!(summary.entries[0].name, "docs_at_000000001.jsonl");
+        assert!(verify_archive(&dir, &summary).is_empty());
+
+        let zip_file = dir.join(&summary.name);
+        let mut batches = Vec::new();
+        let reader = ArchiveReader::create_reader(&zip_file).unwrap();
+        reader
+            .read_batches(
+                1,
+                |_, _| false,
+                |entry, json| {
+                    batches.push((entry, json));
+                    false
+                },
+            )
+            .unwrap();
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[1].0, "docs_at_000000001.jsonl#1..2");
+        assert_eq!(batches[1].1, r#"[{"id":2,  "n":"a\nb"}]"#);
+        assert_eq!(batches[2].0, "docs_at_000000003.jsonl#0..1");
+        assert_eq!(summary.count_batches(1), 3);
+        assert_eq!(summary.count_batches(4), 2);
+
+        // resuming with other --batch-docs leaves out the lines already sent
+        let mut resumed = Vec::new();
+        let reader = ArchiveReader::create_reader(&zip_file).unwrap();
+        let skip_line = |entry: &str, line| entry.ends_with("01.jsonl") && line == 0;
+        reader
+            .read_batches(4, skip_line, |entry, _| {
+                resumed.push(entry);
+                false
+            })
+            .unwrap();
+        assert_eq!(resumed, vec!["docs_at_000000001.jsonl#1..2", "docs_at_000000003.jsonl#0..1"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}